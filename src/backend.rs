@@ -0,0 +1,121 @@
+use anyhow::Result;
+
+mod fs;
+mod git;
+mod memory;
+
+pub use self::fs::FsBackend;
+pub(crate) use self::git::StagedChanges;
+pub use self::git::GitTreeBackend;
+pub use memory::MemoryBackend;
+
+/// A write (`Some`) or delete (`None`) of the file at the given path.
+pub type BatchOp = (String, Option<Vec<u8>>);
+
+/// A flat store of files addressed by `/`-separated paths relative to the
+/// database root, e.g. `collections/users/alice.json` or `certs/alice.cert`.
+///
+/// Paths handed to a backend are always built from escaped IDs, so they never
+/// contain `..` components or start with `.`.
+pub trait StorageBackend: Send + Sync {
+    fn write(&self, path: &str, data: &[u8]) -> Result<()>;
+
+    fn read(&self, path: &str) -> Result<Vec<u8>>;
+
+    fn delete(&self, path: &str) -> Result<()>;
+
+    fn exists(&self, path: &str) -> Result<bool>;
+
+    /// Names of the files directly inside `dir` (`""` for the root).
+    fn list(&self, dir: &str) -> Result<Vec<String>>;
+
+    /// Names of the directories directly inside `dir` (`""` for the root).
+    fn list_dirs(&self, dir: &str) -> Result<Vec<String>>;
+
+    /// Removes `dir` and everything below it.
+    fn delete_dir(&self, dir: &str) -> Result<()>;
+
+    /// Applies the operations in order. Backends that can apply them
+    /// all-or-nothing should override this.
+    fn write_batch(&self, ops: &[BatchOp]) -> Result<()> {
+        for (path, data) in ops {
+            match data {
+                Some(data) => self.write(path, data)?,
+                None => self.delete(path)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn exercise(backend: &dyn StorageBackend) -> Result<()> {
+        backend.write("a.json", b"1")?;
+        backend.write("collections/users/b.json", b"2")?;
+        backend.write("collections/orders/c.json", b"3")?;
+        // Hidden files and directories are readable but never listed
+        backend.write(".hidden", b"h")?;
+        backend.write("collections/.hidden/e.json", b"5")?;
+        assert_eq!(backend.read(".hidden")?, b"h");
+
+        assert_eq!(backend.read("a.json")?, b"1");
+        assert!(backend.exists("collections/users/b.json")?);
+        assert!(!backend.exists("missing.json")?);
+        assert!(backend.read("missing.json").is_err());
+
+        assert_eq!(backend.list("")?, vec!["a.json"]);
+        assert_eq!(backend.list("collections/users")?, vec!["b.json"]);
+        assert!(backend.list("nowhere")?.is_empty());
+        let mut dirs = backend.list_dirs("collections")?;
+        dirs.sort();
+        assert_eq!(dirs, vec!["orders", "users"]);
+
+        backend.write_batch(&[
+            ("a.json".to_string(), Some(b"10".to_vec())),
+            ("d.json".to_string(), Some(b"4".to_vec())),
+            ("collections/users/b.json".to_string(), None),
+        ])?;
+        assert_eq!(backend.read("a.json")?, b"10");
+        assert_eq!(backend.read("d.json")?, b"4");
+        assert!(!backend.exists("collections/users/b.json")?);
+
+        backend.delete_dir("collections/orders")?;
+        assert!(!backend.exists("collections/orders/c.json")?);
+        assert!(backend.delete_dir("collections/orders").is_err());
+
+        backend.delete("a.json")?;
+        assert!(backend.delete("a.json").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fs_backend() -> Result<()> {
+        let dir = tempdir()?;
+        exercise(&FsBackend::new(dir.path())?)
+    }
+
+    #[test]
+    fn test_memory_backend() -> Result<()> {
+        exercise(&MemoryBackend::new())
+    }
+
+    #[test]
+    fn test_git_tree_backend() -> Result<()> {
+        let dir = tempdir()?;
+        let git = crate::GitManager::open_bare(dir.path())?;
+        exercise(&git.tree_backend()?)
+    }
+}
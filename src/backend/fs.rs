@@ -0,0 +1,482 @@
+use super::{BatchOp, StorageBackend};
+use crate::DbError;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+const TEMP_SUFFIX: &str = ".tmp";
+/// Lists the files of a batch being applied, see [`PendingBatch`].
+const JOURNAL_NAME: &str = ".batch.journal";
+
+/// A temporary file holding the complete new contents of `target`, synced to
+/// disk but not yet renamed into place. Dropping it without calling
+/// [`PendingWrite::commit`] removes the temporary file, leaving `target`
+/// untouched.
+struct PendingWrite {
+    temp_path: Option<PathBuf>,
+    target: PathBuf,
+}
+
+impl PendingWrite {
+    fn stage(target: &Path, data: &[u8]) -> Result<Self> {
+        let dir = target.parent().unwrap_or(Path::new("."));
+        let file_name = target.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        // Escaped IDs never start with '.', so temp files can't clash with documents
+        let temp_path = dir.join(format!(".{}.{:016x}{}", file_name, rand::random::<u64>(), TEMP_SUFFIX));
+
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&temp_path)?;
+        let pending = Self {
+            temp_path: Some(temp_path),
+            target: target.to_path_buf(),
+        };
+        file.write_all(data)?;
+        file.sync_all()?;
+
+        Ok(pending)
+    }
+
+    fn commit(mut self) -> Result<()> {
+        if let Some(temp_path) = &self.temp_path {
+            fs::rename(temp_path, &self.target)?;
+            self.temp_path = None;
+            sync_parent_dir(&self.target)?;
+        }
+        Ok(())
+    }
+
+    /// Leaves the temporary file for [`FsBackend::recover`] to move into place.
+    fn keep(mut self) {
+        self.temp_path = None;
+    }
+}
+
+/// A batch of writes staged in temporary files, plus deletions. Once its
+/// journal is on disk the batch counts as written: if applying it is
+/// interrupted, [`FsBackend::recover`] finishes it.
+struct PendingBatch {
+    journal: PathBuf,
+    /// Each target with its staged contents, or `None` to delete it.
+    entries: Vec<(PathBuf, Option<PendingWrite>)>,
+}
+
+impl PendingBatch {
+    fn apply(self) -> Result<()> {
+        let mut entries = self.entries.into_iter();
+        let result = entries.by_ref().try_for_each(|(target, pending)| match pending {
+            Some(pending) => pending.commit(),
+            None => remove_file(&target),
+        });
+        if result.is_err() {
+            entries.filter_map(|(_, pending)| pending).for_each(PendingWrite::keep);
+            return result;
+        }
+        remove_file(&self.journal)
+    }
+}
+
+fn remove_file(path: &Path) -> Result<()> {
+    if path.is_file() {
+        fs::remove_file(path)?;
+        sync_parent_dir(path)?;
+    }
+    Ok(())
+}
+
+impl Drop for PendingWrite {
+    fn drop(&mut self) {
+        if let Some(temp_path) = &self.temp_path {
+            let _ = fs::remove_file(temp_path);
+        }
+    }
+}
+
+/// Makes a rename or unlink in `path`'s directory durable.
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+fn is_temp_file(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(TEMP_SUFFIX)
+}
+
+/// Whether `name` is a temporary file [`PendingWrite::stage`] creates for
+/// `target_name`, i.e. `.<target_name>.<16 hex digits>.tmp`.
+fn is_temp_file_for(name: &str, target_name: &str) -> bool {
+    name.strip_prefix('.')
+        .and_then(|name| name.strip_prefix(target_name))
+        .and_then(|name| name.strip_prefix('.'))
+        .and_then(|name| name.strip_suffix(TEMP_SUFFIX))
+        .is_some_and(|random| random.len() == 16 && random.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Stores files in a directory on disk. Writes are atomic: a crash leaves
+/// either the old or the new contents of a file, never a partial write, and
+/// either all or none of the changes of a batch.
+pub struct FsBackend {
+    root: PathBuf,
+}
+
+impl FsBackend {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let root = path.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+
+        let backend = Self { root };
+        backend.recover()?;
+        Ok(backend)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Finishes a batch that was interrupted while being applied, then
+    /// removes temporary files left behind by writes that were interrupted
+    /// before being renamed into place. Returns the number of files removed.
+    pub fn recover(&self) -> Result<usize> {
+        let journal = self.root.join(JOURNAL_NAME);
+        if journal.is_file() {
+            let entries: Vec<(String, Option<String>)> = serde_json::from_slice(&fs::read(&journal)?)
+                .map_err(|e| DbError::Storage(format!("Invalid batch journal: {}", e)))?;
+            for (target, temp) in entries {
+                let target = self.resolve(&target)?;
+                match temp {
+                    Some(temp) => {
+                        let temp = self.resolve(&temp)?;
+                        let staged_for_target = temp.parent() == target.parent()
+                            && temp.file_name().and_then(|name| name.to_str())
+                                .zip(target.file_name().and_then(|name| name.to_str()))
+                                .is_some_and(|(name, target_name)| is_temp_file_for(name, target_name));
+                        if !staged_for_target {
+                            return Err(DbError::Storage(format!("Invalid batch journal: {} isn't staged for {}", temp.display(), target.display())).into());
+                        }
+                        if temp.is_file() {
+                            fs::rename(&temp, &target)?;
+                            sync_parent_dir(&target)?;
+                        }
+                    },
+                    None => remove_file(&target)?,
+                }
+            }
+            remove_file(&journal)?;
+        }
+
+        fn walk(dir: &Path, removed: &mut usize) -> Result<()> {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() && !name.starts_with('.') {
+                    walk(&entry.path(), removed)?;
+                } else if file_type.is_file() && is_temp_file(&name) {
+                    fs::remove_file(entry.path())?;
+                    *removed += 1;
+                }
+            }
+            Ok(())
+        }
+
+        let mut removed = 0;
+        walk(&self.root, &mut removed)?;
+        Ok(removed)
+    }
+
+    fn resolve(&self, path: &str) -> Result<PathBuf> {
+        let mut resolved = self.root.clone();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if component == "." || component == ".." || component.contains('\\') {
+                return Err(DbError::Storage(format!("Invalid path: {}", path)).into());
+            }
+            resolved.push(component);
+        }
+        Ok(resolved)
+    }
+
+    /// Stages every write of `ops` and records the batch in the journal,
+    /// without touching any target yet.
+    fn stage_batch(&self, ops: &[BatchOp]) -> Result<PendingBatch> {
+        let mut entries = Vec::new();
+        let mut journal = Vec::new();
+        let mut exists = BTreeMap::new();
+        for (path, data) in ops {
+            let target = self.resolve(path)?;
+            let present = exists.get(path).copied().unwrap_or_else(|| target.is_file());
+            exists.insert(path, data.is_some());
+            match data {
+                Some(data) => {
+                    if let Some(dir) = target.parent() {
+                        fs::create_dir_all(dir)?;
+                    }
+                    let pending = PendingWrite::stage(&target, data)?;
+                    let temp = pending.temp_path.as_ref().and_then(|temp| temp.strip_prefix(&self.root).ok());
+                    journal.push((path.clone(), temp.map(|temp| temp.to_string_lossy().into_owned())));
+                    entries.push((target, Some(pending)));
+                },
+                None if !present => return Err(DbError::Storage(format!("File not found: {}", path)).into()),
+                None => {
+                    journal.push((path.clone(), None));
+                    entries.push((target, None));
+                },
+            }
+        }
+
+        // Linked rather than renamed into place, which fails instead of
+        // replacing the journal of a batch another writer is applying
+        let journal_path = self.root.join(JOURNAL_NAME);
+        let staged = PendingWrite::stage(&journal_path, &serde_json::to_vec(&journal)?)?;
+        if let Some(temp) = &staged.temp_path {
+            match fs::hard_link(temp, &journal_path) {
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    return Err(DbError::Storage("Another batch is being written; try again once it has finished".to_string()).into());
+                },
+                result => result?,
+            }
+        }
+        drop(staged);
+        sync_parent_dir(&journal_path)?;
+        Ok(PendingBatch { journal: journal_path, entries })
+    }
+
+    fn entries(&self, dir: &str, want_dirs: bool) -> Result<Vec<String>> {
+        let dir = self.resolve(dir)?;
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() != want_dirs {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                // Skips .git, temporary files and other hidden entries
+                if !name.starts_with('.') {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+impl StorageBackend for FsBackend {
+    fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        let path = self.resolve(path)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        PendingWrite::stage(&path, data)?.commit()
+    }
+
+    fn read(&self, path: &str) -> Result<Vec<u8>> {
+        Ok(fs::read(self.resolve(path)?)?)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        let path = self.resolve(path)?;
+        fs::remove_file(&path)?;
+        sync_parent_dir(&path)?;
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        Ok(self.resolve(path)?.is_file())
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>> {
+        self.entries(dir, false)
+    }
+
+    fn list_dirs(&self, dir: &str) -> Result<Vec<String>> {
+        self.entries(dir, true)
+    }
+
+    fn delete_dir(&self, dir: &str) -> Result<()> {
+        let path = self.resolve(dir)?;
+        if !path.is_dir() || path == self.root {
+            return Err(DbError::Storage(format!("Directory not found: {}", dir)).into());
+        }
+        fs::remove_dir_all(&path)?;
+        sync_parent_dir(&path)?;
+        Ok(())
+    }
+
+    /// Writes the new contents of every file before replacing any, so that
+    /// a failure or crash leaves either all or none of the changes.
+    fn write_batch(&self, ops: &[BatchOp]) -> Result<()> {
+        self.stage_batch(ops)?.apply()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn temp_files(dir: &Path) -> Vec<String> {
+        fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| is_temp_file(name))
+            .collect()
+    }
+
+    #[test]
+    fn test_interrupted_writes_never_corrupt() {
+        let dir = tempdir().unwrap();
+        let old = br#"{"id":"doc","version":"old"}"#;
+        let new = br#"{"id":"doc","version":"new and longer"}"#;
+        FsBackend::new(dir.path()).unwrap().write("doc.json", old).unwrap();
+        let target = dir.path().join("doc.json");
+
+        // Crash at every point while the temporary file is being filled in,
+        // and after it is complete but before the rename
+        for len in 0..=new.len() {
+            let pending = PendingWrite::stage(&target, &new[..len]).unwrap();
+            std::mem::forget(pending);
+
+            // Reopening the database is what happens after the crash
+            let backend = FsBackend::new(dir.path()).unwrap();
+            assert_eq!(backend.read("doc.json").unwrap(), old);
+            assert_eq!(backend.list("").unwrap(), vec!["doc.json"]);
+            assert!(temp_files(dir.path()).is_empty());
+        }
+
+        // Crash right after the rename: the new contents are complete
+        let pending = PendingWrite::stage(&target, new).unwrap();
+        pending.commit().unwrap();
+        let backend = FsBackend::new(dir.path()).unwrap();
+        assert_eq!(backend.read("doc.json").unwrap(), new);
+    }
+
+    #[test]
+    fn test_failed_write_cleans_up() {
+        let dir = tempdir().unwrap();
+        let backend = FsBackend::new(dir.path()).unwrap();
+
+        // A target that can't be replaced makes the rename fail
+        fs::create_dir_all(dir.path().join("blocked.json/inner")).unwrap();
+        assert!(backend.write("blocked.json", b"new").is_err());
+        assert!(temp_files(dir.path()).is_empty());
+        assert!(dir.path().join("blocked.json/inner").is_dir());
+    }
+
+    #[test]
+    fn test_recover_removes_stale_temp_files() {
+        let dir = tempdir().unwrap();
+        let backend = FsBackend::new(dir.path()).unwrap();
+        backend.write("collections/users/a.json", b"{}").unwrap();
+
+        fs::write(dir.path().join(".doc.json.0123456789abcdef.tmp"), b"partial").unwrap();
+        fs::write(dir.path().join("collections/users/.a.json.0123456789abcdef.tmp"), b"").unwrap();
+        fs::write(dir.path().join("notes.tmp"), b"not ours").unwrap();
+
+        assert_eq!(backend.recover().unwrap(), 2);
+        assert!(dir.path().join("notes.tmp").exists());
+        assert_eq!(backend.read("collections/users/a.json").unwrap(), b"{}");
+    }
+
+    #[test]
+    fn test_interrupted_batch_is_finished_on_open() {
+        let dir = tempdir().unwrap();
+        let backend = FsBackend::new(dir.path()).unwrap();
+        backend.write("a.json", b"old a").unwrap();
+        backend.write("b.json", b"old b").unwrap();
+        let ops: Vec<BatchOp> = vec![
+            ("a.json".to_string(), Some(b"new a".to_vec())),
+            ("keys/c".to_string(), Some(b"new c".to_vec())),
+            ("b.json".to_string(), None),
+        ];
+
+        // Crash after the first file was moved into place
+        let batch = backend.stage_batch(&ops).unwrap();
+        let mut entries = batch.entries.into_iter();
+        let (_, first) = entries.next().unwrap();
+        first.unwrap().commit().unwrap();
+        entries.filter_map(|(_, pending)| pending).for_each(PendingWrite::keep);
+        assert_eq!(backend.read("b.json").unwrap(), b"old b");
+
+        let backend = FsBackend::new(dir.path()).unwrap();
+        assert_eq!(backend.read("a.json").unwrap(), b"new a");
+        assert_eq!(backend.read("keys/c").unwrap(), b"new c");
+        assert!(!backend.exists("b.json").unwrap());
+        assert!(!dir.path().join(JOURNAL_NAME).exists());
+        assert!(temp_files(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn test_journal_only_names_staged_files() {
+        let dir = tempdir().unwrap();
+        let backend = FsBackend::new(dir.path()).unwrap();
+        backend.write("a.json", b"old").unwrap();
+        backend.write("b.json", b"other").unwrap();
+
+        for temp in ["../outside.tmp", "b.json", ".b.json.0123456789abcdef.tmp", "keys/.a.json.0123456789abcdef.tmp"] {
+            let journal = serde_json::to_vec(&[("a.json", Some(temp))]).unwrap();
+            fs::write(dir.path().join(JOURNAL_NAME), journal).unwrap();
+            assert!(FsBackend::new(dir.path()).is_err());
+            assert_eq!(backend.read("a.json").unwrap(), b"old");
+            assert_eq!(backend.read("b.json").unwrap(), b"other");
+        }
+        assert!(is_temp_file_for(".a.json.0123456789abcdef.tmp", "a.json"));
+    }
+
+    #[test]
+    fn test_concurrent_batch_is_refused() {
+        let dir = tempdir().unwrap();
+        let backend = FsBackend::new(dir.path()).unwrap();
+        let ops: Vec<BatchOp> = vec![("a.json".to_string(), Some(b"first".to_vec()))];
+        let batch = backend.stage_batch(&ops).unwrap();
+
+        let ops: Vec<BatchOp> = vec![("a.json".to_string(), Some(b"second".to_vec()))];
+        assert!(backend.write_batch(&ops).is_err());
+        batch.apply().unwrap();
+        assert_eq!(backend.read("a.json").unwrap(), b"first");
+        assert!(temp_files(dir.path()).is_empty());
+        backend.write_batch(&ops).unwrap();
+    }
+
+    #[test]
+    fn test_failed_batch_changes_nothing() {
+        let dir = tempdir().unwrap();
+        let backend = FsBackend::new(dir.path()).unwrap();
+        backend.write("a.json", b"old").unwrap();
+
+        let ops: Vec<BatchOp> = vec![
+            ("a.json".to_string(), Some(b"new".to_vec())),
+            ("missing.json".to_string(), None),
+        ];
+        assert!(backend.write_batch(&ops).is_err());
+        // A path below a file can't be written
+        let ops: Vec<BatchOp> = vec![
+            ("a.json".to_string(), Some(b"new".to_vec())),
+            ("a.json/b".to_string(), Some(b"new".to_vec())),
+        ];
+        assert!(backend.write_batch(&ops).is_err());
+
+        assert_eq!(backend.read("a.json").unwrap(), b"old");
+        assert!(temp_files(dir.path()).is_empty());
+        assert!(!dir.path().join(JOURNAL_NAME).exists());
+    }
+
+    #[test]
+    fn test_rejects_escaping_paths() {
+        let dir = tempdir().unwrap();
+        let backend = FsBackend::new(dir.path().join("db")).unwrap();
+
+        assert!(backend.write("../outside.json", b"x").is_err());
+        assert!(backend.read("a/../../outside.json").is_err());
+        assert!(backend.delete_dir("").is_err());
+        assert!(!dir.path().join("outside.json").exists());
+    }
+}
@@ -0,0 +1,158 @@
+use super::{join, StorageBackend};
+use crate::DbError;
+use anyhow::Result;
+use git2::{ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Blobs written since the last commit, keyed by path. `None` marks a deletion.
+pub(crate) type StagedChanges = Arc<Mutex<BTreeMap<String, Option<Oid>>>>;
+
+/// Stores files directly in a git object database. Reads come from the tree of
+/// HEAD, or of `branch` if given, overlaid with blobs staged since the last
+/// commit; writes only create blobs. [`crate::GitManager::commit`] turns the
+/// staged blobs into a tree.
+pub struct GitTreeBackend {
+    repo: Mutex<Repository>,
+    staged: StagedChanges,
+    branch: Option<String>,
+}
+
+impl GitTreeBackend {
+    pub(crate) fn new(repo: Repository, staged: StagedChanges, branch: Option<String>) -> Self {
+        Self {
+            repo: Mutex::new(repo),
+            staged,
+            branch,
+        }
+    }
+
+    fn not_found(path: &str) -> anyhow::Error {
+        DbError::Storage(format!("File not found: {}", path)).into()
+    }
+
+    fn head_tree<'r>(&self, repo: &'r Repository) -> Result<Option<git2::Tree<'r>>> {
+        let head = match &self.branch {
+            Some(branch) => repo.find_reference(&format!("refs/heads/{}", branch)),
+            None => repo.head(),
+        };
+        match head {
+            Ok(head) => Ok(Some(head.peel_to_tree()?)),
+            Err(e) if matches!(e.code(), git2::ErrorCode::UnbornBranch | git2::ErrorCode::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The blob currently stored at `path`, taking staged changes into account.
+    fn blob_id(&self, repo: &Repository, path: &str) -> Result<Option<Oid>> {
+        if let Some(staged) = self.staged.lock().unwrap().get(path) {
+            return Ok(*staged);
+        }
+        let tree = match self.head_tree(repo)? {
+            Some(tree) => tree,
+            None => return Ok(None),
+        };
+        match tree.get_path(Path::new(path)) {
+            Ok(entry) if entry.kind() == Some(ObjectType::Blob) => Ok(Some(entry.id())),
+            Ok(_) => Ok(None),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Paths of every file below `dir`, relative to `dir`.
+    fn files_below(&self, dir: &str) -> Result<BTreeSet<String>> {
+        let repo = self.repo.lock().unwrap();
+        let mut files = BTreeSet::new();
+
+        if let Some(tree) = self.head_tree(&repo)? {
+            let subtree = if dir.is_empty() {
+                Some(tree)
+            } else {
+                match tree.get_path(Path::new(dir)) {
+                    Ok(entry) if entry.kind() == Some(ObjectType::Tree) => Some(repo.find_tree(entry.id())?),
+                    _ => None,
+                }
+            };
+            if let Some(subtree) = subtree {
+                subtree.walk(TreeWalkMode::PreOrder, |parent, entry| {
+                    if entry.kind() == Some(ObjectType::Blob) {
+                        if let Some(name) = entry.name() {
+                            files.insert(format!("{}{}", parent, name));
+                        }
+                    }
+                    TreeWalkResult::Ok
+                })?;
+            }
+        }
+
+        let prefix = join(dir, "");
+        for (path, blob) in self.staged.lock().unwrap().iter() {
+            if let Some(rest) = path.strip_prefix(prefix.as_str()) {
+                match blob {
+                    Some(_) => files.insert(rest.to_string()),
+                    None => files.remove(rest),
+                };
+            }
+        }
+
+        Ok(files)
+    }
+}
+
+impl StorageBackend for GitTreeBackend {
+    fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        let oid = self.repo.lock().unwrap().blob(data)?;
+        self.staged.lock().unwrap().insert(path.to_string(), Some(oid));
+        Ok(())
+    }
+
+    fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let repo = self.repo.lock().unwrap();
+        let oid = self.blob_id(&repo, path)?.ok_or_else(|| Self::not_found(path))?;
+        let data = repo.find_blob(oid)?.content().to_vec();
+        Ok(data)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        if !self.exists(path)? {
+            return Err(Self::not_found(path));
+        }
+        self.staged.lock().unwrap().insert(path.to_string(), None);
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        let repo = self.repo.lock().unwrap();
+        Ok(self.blob_id(&repo, path)?.is_some())
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>> {
+        Ok(self.files_below(dir)?
+            .into_iter()
+            .filter(|path| !path.contains('/') && !path.starts_with('.'))
+            .collect())
+    }
+
+    fn list_dirs(&self, dir: &str) -> Result<Vec<String>> {
+        let dirs: BTreeSet<String> = self.files_below(dir)?
+            .into_iter()
+            .filter_map(|path| path.split_once('/').map(|(name, _)| name.to_string()))
+            .filter(|name| !name.starts_with('.'))
+            .collect();
+        Ok(dirs.into_iter().collect())
+    }
+
+    fn delete_dir(&self, dir: &str) -> Result<()> {
+        let files = self.files_below(dir)?;
+        if dir.is_empty() || files.is_empty() {
+            return Err(DbError::Storage(format!("Directory not found: {}", dir)).into());
+        }
+        let mut staged = self.staged.lock().unwrap();
+        for file in files {
+            staged.insert(join(dir, &file), None);
+        }
+        Ok(())
+    }
+}
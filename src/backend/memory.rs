@@ -0,0 +1,101 @@
+use super::{join, BatchOp, StorageBackend};
+use crate::DbError;
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::RwLock;
+
+/// Keeps every file in memory. Useful for tests and short-lived databases.
+#[derive(Default)]
+pub struct MemoryBackend {
+    files: RwLock<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn not_found(path: &str) -> anyhow::Error {
+        DbError::Storage(format!("File not found: {}", path)).into()
+    }
+
+    /// Iterates over the paths below `dir`, yielding the part after `dir/`.
+    fn entries<'a>(files: &'a BTreeMap<String, Vec<u8>>, dir: &str) -> impl Iterator<Item = &'a str> {
+        let prefix = join(dir, "");
+        files.keys().filter_map(move |path| path.strip_prefix(prefix.as_str()))
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        self.files.write().unwrap().insert(path.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn read(&self, path: &str) -> Result<Vec<u8>> {
+        self.files.read().unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| Self::not_found(path))
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.files.write().unwrap()
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| Self::not_found(path))
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        Ok(self.files.read().unwrap().contains_key(path))
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>> {
+        let files = self.files.read().unwrap();
+        Ok(Self::entries(&files, dir)
+            .filter(|rest| !rest.contains('/') && !rest.starts_with('.'))
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn list_dirs(&self, dir: &str) -> Result<Vec<String>> {
+        let files = self.files.read().unwrap();
+        let dirs: BTreeSet<&str> = Self::entries(&files, dir)
+            .filter_map(|rest| rest.split_once('/').map(|(name, _)| name))
+            .filter(|name| !name.starts_with('.'))
+            .collect();
+        Ok(dirs.into_iter().map(str::to_string).collect())
+    }
+
+    fn delete_dir(&self, dir: &str) -> Result<()> {
+        let prefix = join(dir, "");
+        let mut files = self.files.write().unwrap();
+        let before = files.len();
+        files.retain(|path, _| !path.starts_with(&prefix));
+        if files.len() == before {
+            return Err(DbError::Storage(format!("Directory not found: {}", dir)).into());
+        }
+        Ok(())
+    }
+
+    fn write_batch(&self, ops: &[BatchOp]) -> Result<()> {
+        let mut files = self.files.write().unwrap();
+        // Check every delete up front so the batch applies all-or-nothing
+        let mut exists: BTreeMap<&str, bool> = BTreeMap::new();
+        for (path, data) in ops {
+            let present = exists.get(path.as_str()).copied().unwrap_or_else(|| files.contains_key(path));
+            if data.is_none() && !present {
+                return Err(Self::not_found(path));
+            }
+            exists.insert(path, data.is_some());
+        }
+
+        for (path, data) in ops {
+            match data {
+                Some(data) => { files.insert(path.clone(), data.clone()); }
+                None => { files.remove(path); }
+            }
+        }
+        Ok(())
+    }
+}
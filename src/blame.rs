@@ -0,0 +1,128 @@
+use crate::diff::dotted;
+use crate::{CommitInfo, DocumentDiff, Revision};
+use serde::Serialize;
+use serde_json::Value;
+
+/// The commit that last changed one leaf value of a document.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldBlame {
+    /// Path segments from the document root; array indices are rendered as numbers.
+    pub path: Vec<String>,
+    pub value: Value,
+    pub commit: CommitInfo,
+}
+
+impl FieldBlame {
+    /// The path in the dotted form accepted by filters, e.g. `address.city`.
+    pub fn dotted_path(&self) -> String {
+        dotted(&self.path)
+    }
+}
+
+/// Blames every leaf of the latest revision in `history` (newest first, as
+/// returned by `history`). Only the document's current life counts: if it was
+/// deleted and created again, everything before the re-creation is ignored.
+/// Returns `None` if the document doesn't exist in the latest revision.
+pub(crate) fn blame(history: &[Revision]) -> Option<Vec<FieldBlame>> {
+    let current = history.first()?.document.as_ref()?;
+    let lifetime = history.iter()
+        .position(|revision| revision.document.is_none())
+        .unwrap_or(history.len());
+
+    // Paths each revision touched, newest first
+    let touched: Vec<(&CommitInfo, Vec<Vec<String>>)> = (0..lifetime)
+        .map(|i| {
+            let previous = history.get(i + 1)
+                .filter(|_| i + 1 < lifetime)
+                .and_then(|revision| revision.document.as_ref());
+            let diff = DocumentDiff::between(
+                previous.map(|doc| &doc.data),
+                history[i].document.as_ref().map(|doc| &doc.data),
+            );
+            (&history[i].commit, diff.changes.into_iter().map(|change| change.path).collect())
+        })
+        .collect();
+
+    let mut leaves = Vec::new();
+    collect_leaves(&mut Vec::new(), &current.data, &mut leaves);
+    Some(leaves.into_iter()
+        .map(|(path, value)| {
+            // A change to the leaf itself, to something containing it or to
+            // something that used to be inside it (which made it a leaf)
+            let commit = touched.iter()
+                .find(|(_, paths)| paths.iter().any(|changed| related(changed, &path)))
+                .or(touched.last())
+                .map(|(commit, _)| (*commit).clone())
+                .unwrap_or_else(|| history[0].commit.clone());
+            FieldBlame { path, value: value.clone(), commit }
+        })
+        .collect())
+}
+
+fn related(a: &[String], b: &[String]) -> bool {
+    a.iter().zip(b).all(|(a, b)| a == b)
+}
+
+/// Scalars, empty objects and empty arrays, in document order.
+fn collect_leaves<'a>(path: &mut Vec<String>, value: &'a Value, leaves: &mut Vec<(Vec<String>, &'a Value)>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map {
+                path.push(key.clone());
+                collect_leaves(path, value, leaves);
+                path.pop();
+            }
+        },
+        Value::Array(items) if !items.is_empty() => {
+            for (i, value) in items.iter().enumerate() {
+                path.push(i.to_string());
+                collect_leaves(path, value, leaves);
+                path.pop();
+            }
+        },
+        _ => leaves.push((path.clone(), value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Document;
+    use serde_json::json;
+
+    fn revision(commit: &str, data: Option<Value>) -> Revision {
+        Revision {
+            commit: CommitInfo {
+                id: commit.to_string(),
+                message: String::new(),
+                author: commit.to_string(),
+                timestamp: 0,
+            },
+            document: data.map(|data| Document { id: "a".to_string(), data, created_at: 0, updated_at: 0, version: 1 }),
+        }
+    }
+
+    fn summary(history: &[Revision]) -> Vec<(String, String)> {
+        blame(history).unwrap().iter().map(|b| (b.dotted_path(), b.commit.id.clone())).collect()
+    }
+
+    #[test]
+    fn test_blame_leaves() {
+        let history = vec![
+            revision("c4", Some(json!({ "name": "Alicia", "tags": ["x"], "address": {} }))),
+            revision("c3", Some(json!({ "name": "Alicia", "tags": ["x", "y"], "address": { "city": "Oslo" } }))),
+            revision("c2", Some(json!({ "name": "Alicia", "tags": ["x", "y"] }))),
+            revision("c1", Some(json!({ "name": "Alice", "tags": ["x", "y"] }))),
+            revision("c0", None),
+            revision("old", Some(json!({ "name": "Alicia" }))),
+        ];
+        assert_eq!(summary(&history), vec![
+            ("address".to_string(), "c4".to_string()),
+            ("name".to_string(), "c2".to_string()),
+            ("tags.0".to_string(), "c1".to_string()),
+        ]);
+
+        assert!(blame(&history[4..]).is_none());
+        assert_eq!(summary(&history[5..]), vec![("name".to_string(), "old".to_string())]);
+    }
+}
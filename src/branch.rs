@@ -0,0 +1,129 @@
+use crate::merge::{MergeCommits, Resolver};
+use crate::{DbError, DocumentConflict, GitManager};
+use anyhow::Result;
+use git2::{build::CheckoutBuilder, BranchType};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MergeOutcome {
+    /// The target branch already contained every commit of the source.
+    UpToDate,
+    /// The target branch had no commits of its own and was moved forward.
+    FastForward,
+    /// A merge commit was created. Holds the documents whose conflicting
+    /// fields were settled by the merge policy (filled in by
+    /// [`crate::GithubDb::merge_branch`]).
+    Merged(Vec<DocumentConflict>),
+}
+
+impl GitManager {
+    /// The branch this handle works on: the one set with
+    /// [`GitManager::set_branch`], otherwise the one HEAD points at.
+    pub fn current_branch(&self) -> Result<String> {
+        if let Some(branch) = &self.branch {
+            return Ok(branch.clone());
+        }
+        let head = self.repo.find_reference("HEAD")?;
+        let target = head.symbolic_target()
+            .ok_or_else(|| DbError::Git("HEAD is detached".to_string()))?;
+        Ok(target.strip_prefix("refs/heads/").unwrap_or(target).to_string())
+    }
+
+    /// Updates the working tree to match HEAD. A no-op for bare repositories
+    /// and for handles on another branch, which don't use the working tree.
+    pub(crate) fn checkout_worktree(&self) -> Result<()> {
+        if self.staged.is_none() {
+            self.repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+        }
+        Ok(())
+    }
+
+    /// Points HEAD at `name` and updates the working tree, so that reads and
+    /// commits of handles following HEAD use that branch. In a repository
+    /// without commits the branch doesn't need to exist yet. To work on a
+    /// branch without switching, see [`GitManager::set_branch`].
+    pub fn checkout(&self, name: &str) -> Result<()> {
+        let refname = format!("refs/heads/{}", name);
+        let exists = self.repo.find_branch(name, BranchType::Local).is_ok();
+        if !exists && !self.repo.is_empty()? {
+            return Err(DbError::Git(format!("Branch {} does not exist", name)).into());
+        }
+        if !git2::Reference::is_valid_name(&refname) {
+            return Err(DbError::Git(format!("Invalid branch name {}", name)).into());
+        }
+
+        self.repo.set_head(&refname)?;
+        if exists && !self.is_bare() {
+            self.repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+        }
+        Ok(())
+    }
+
+    /// Creates `name` at `from` (any revision, defaulting to HEAD).
+    pub fn create_branch(&self, name: &str, from: Option<&str>) -> Result<()> {
+        let start = self.resolve(from.unwrap_or("HEAD"))?;
+        if self.repo.find_branch(name, BranchType::Local).is_ok() {
+            return Err(DbError::Git(format!("Branch {} already exists", name)).into());
+        }
+        self.repo.branch(name, &start, false)?;
+        Ok(())
+    }
+
+    pub fn list_branches(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for branch in self.repo.branches(Some(BranchType::Local))? {
+            if let Some(name) = branch?.0.name()? {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn delete_branch(&self, name: &str) -> Result<()> {
+        if self.current_branch()? == name {
+            return Err(DbError::Git(format!("Cannot delete the current branch {}", name)).into());
+        }
+        let mut branch = self.repo.find_branch(name, BranchType::Local)
+            .map_err(|_| DbError::Git(format!("Branch {} does not exist", name)))?;
+        branch.delete()?;
+        Ok(())
+    }
+
+    /// Merges `source` into the current branch. Files changed on both sides
+    /// are handed to `resolver`; nothing is committed if it fails.
+    pub(crate) fn merge_branch(&self, source: &str, resolver: &mut Resolver) -> Result<MergeOutcome> {
+        let source_commit = self.resolve(source)?;
+        let target = self.current_branch()?;
+        let head = match self.head() {
+            Ok(head) => head.peel_to_commit()?,
+            Err(_) => {
+                self.repo.reference(&format!("refs/heads/{}", target), source_commit.id(), false, "github-db: merge")?;
+                self.checkout_worktree()?;
+                return Ok(MergeOutcome::FastForward);
+            },
+        };
+
+        let base = self.repo.merge_base(head.id(), source_commit.id())?;
+        if base == source_commit.id() {
+            return Ok(MergeOutcome::UpToDate);
+        }
+        if base == head.id() {
+            self.repo.reference(&format!("refs/heads/{}", target), source_commit.id(), true, "github-db: merge")?;
+            self.checkout_worktree()?;
+            return Ok(MergeOutcome::FastForward);
+        }
+
+        let mut index = self.repo.merge_commits(&head, &source_commit, None)?;
+        if index.has_conflicts() {
+            let commits = MergeCommits { base: Some(base), ours: head.id(), theirs: source_commit.id() };
+            self.resolve_conflicts(&mut index, &commits, resolver)?;
+        }
+
+        let tree = self.repo.find_tree(index.write_tree_to(&self.repo)?)?;
+        let message = format!("Merge branch {} into {}", source, target);
+        self.create_commit(Some(&self.head_ref()), &self.author()?, &self.committer()?, &message, &tree, &[&head, &source_commit])?;
+        self.checkout_worktree()?;
+
+        Ok(MergeOutcome::Merged(Vec::new()))
+    }
+}
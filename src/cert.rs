@@ -1,22 +1,21 @@
 use anyhow::Result;
 use openssl::{
-    pkey::{PKey, Private, Public},
+    pkey::PKey,
     rsa::Rsa,
-    x509::{X509Builder, X509},
+    x509::{extension::SubjectAlternativeName, X509Builder, X509},
 };
-use std::{fs, path::{Path, PathBuf}};
-use crate::{DbError, Crypto};
+use std::sync::Arc;
+use crate::{DbError, Crypto, DocumentId, Identity, StorageBackend};
+
+pub(crate) const CERTS_DIR: &str = "certs";
 
 pub struct CertManager {
-    certs_path: PathBuf,
+    backend: Arc<dyn StorageBackend>,
     crypto: Option<Crypto>,
 }
 
 impl CertManager {
-    pub fn new<P: AsRef<Path>>(path: P, encryption_key: Option<&[u8]>) -> Result<Self> {
-        let certs_path = path.as_ref().join("certs");
-        fs::create_dir_all(&certs_path)?;
-        
+    pub fn new(backend: Arc<dyn StorageBackend>, encryption_key: Option<&[u8]>) -> Result<Self> {
         let crypto = if let Some(key) = encryption_key {
             Some(Crypto::new(key)?)
         } else {
@@ -24,28 +23,51 @@ impl CertManager {
         };
 
         Ok(Self { 
-            certs_path,
+            backend,
             crypto,
         })
     }
 
-    fn encrypt_data(&self, data: &[u8]) -> Result<Vec<u8>> {
+    pub(crate) fn set_crypto(&mut self, crypto: Crypto) {
+        self.crypto = Some(crypto);
+    }
+
+    /// Encrypts a file stored at `path`, bound to that path.
+    pub(crate) fn encrypt_data(&self, path: &str, data: &[u8]) -> Result<Vec<u8>> {
         if let Some(crypto) = &self.crypto {
-            crypto.encrypt(data)
+            crypto.encrypt_for(data, path.as_bytes())
         } else {
             Ok(data.to_vec())
         }
     }
 
-    fn decrypt_data(&self, data: &[u8]) -> Result<Vec<u8>> {
+    pub(crate) fn cert_paths(&self, username: &str) -> Result<(String, String)> {
+        let name = DocumentId::new(username)?.escaped();
+        Ok((
+            format!("{}/{}.cert", CERTS_DIR, name),
+            format!("{}/{}.key", CERTS_DIR, name),
+        ))
+    }
+
+    pub(crate) fn decrypt_data(&self, path: &str, data: &[u8]) -> Result<Vec<u8>> {
         if let Some(crypto) = &self.crypto {
-            crypto.decrypt(data)
+            crypto.decrypt_for(data, path.as_bytes())
+        } else if Crypto::is_encrypted(data) {
+            Err(DbError::Encryption("Certificate store is encrypted, an encryption key is required".to_string()).into())
         } else {
             Ok(data.to_vec())
         }
     }
 
     pub fn generate_cert(&self, username: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+        self.generate_cert_with_email(username, None)
+    }
+
+    /// Like [`CertManager::generate_cert`], additionally recording `email` as
+    /// a subject alternative name.
+    pub fn generate_cert_with_email(&self, username: &str, email: Option<&str>) -> Result<(Vec<u8>, Vec<u8>)> {
+        let (cert_path, key_path) = self.cert_paths(username)?;
+
         // Generate RSA key pair
         let rsa = Rsa::generate(2048)?;
         let private_key = PKey::from_rsa(rsa)?;
@@ -66,6 +88,13 @@ impl CertManager {
         builder.set_not_before(&not_before)?;
         builder.set_not_after(&not_after)?;
 
+        if let Some(email) = email {
+            let san = SubjectAlternativeName::new()
+                .email(email)
+                .build(&builder.x509v3_context(None, None))?;
+            builder.append_extension(san)?;
+        }
+
         // Sign the certificate
         builder.sign(&private_key, openssl::hash::MessageDigest::sha256())?;
         let certificate = builder.build();
@@ -75,14 +104,11 @@ impl CertManager {
         let key_pem = private_key.private_key_to_pem_pkcs8()?;
 
         // Encrypt and save certificate and private key
-        let encrypted_cert = self.encrypt_data(&cert_pem)?;
-        let encrypted_key = self.encrypt_data(&key_pem)?;
+        let encrypted_cert = self.encrypt_data(&cert_path, &cert_pem)?;
+        let encrypted_key = self.encrypt_data(&key_path, &key_pem)?;
 
-        let cert_path = self.certs_path.join(format!("{}.cert", username));
-        let key_path = self.certs_path.join(format!("{}.key", username));
-        
-        fs::write(&cert_path, &encrypted_cert)?;
-        fs::write(&key_path, &encrypted_key)?;
+        self.backend.write(&cert_path, &encrypted_cert)?;
+        self.backend.write(&key_path, &encrypted_key)?;
 
         Ok((cert_pem, key_pem))
     }
@@ -92,14 +118,14 @@ impl CertManager {
             .map_err(|e| DbError::Storage(format!("Invalid certificate: {}", e)))?;
 
         // Check if certificate exists in our store
-        let stored_cert_path = self.certs_path.join(format!("{}.cert", username));
-        if !stored_cert_path.exists() {
+        let (stored_cert_path, _) = self.cert_paths(username)?;
+        if !self.backend.exists(&stored_cert_path)? {
             return Ok(false);
         }
 
         // Read and decrypt stored certificate
-        let encrypted_cert_data = fs::read(&stored_cert_path)?;
-        let stored_cert_data = self.decrypt_data(&encrypted_cert_data)?;
+        let encrypted_cert_data = self.backend.read(&stored_cert_path)?;
+        let stored_cert_data = self.decrypt_data(&stored_cert_path, &encrypted_cert_data)?;
         let stored_cert = X509::from_pem(&stored_cert_data)?;
 
         // Compare certificates
@@ -107,14 +133,12 @@ impl CertManager {
     }
 
     pub fn revoke_cert(&self, username: &str) -> Result<()> {
-        let cert_path = self.certs_path.join(format!("{}.cert", username));
-        let key_path = self.certs_path.join(format!("{}.key", username));
-        
-        if cert_path.exists() {
-            fs::remove_file(cert_path)?;
+        let (cert_path, key_path) = self.cert_paths(username)?;
+        if self.backend.exists(&cert_path)? {
+            self.backend.delete(&cert_path)?;
         }
-        if key_path.exists() {
-            fs::remove_file(key_path)?;
+        if self.backend.exists(&key_path)? {
+            self.backend.delete(&key_path)?;
         }
         
         Ok(())
@@ -122,29 +146,41 @@ impl CertManager {
 
     pub fn list_certs(&self) -> Result<Vec<String>> {
         let mut certs = Vec::new();
-        for entry in fs::read_dir(&self.certs_path)? {
-            let entry = entry?;
-            let file_name = entry.file_name();
-            let file_name = file_name.to_string_lossy();
-            if file_name.ends_with(".cert") {
-                if let Some(username) = file_name.strip_suffix(".cert") {
-                    certs.push(username.to_string());
-                }
+        for file_name in self.backend.list(CERTS_DIR)? {
+            if let Some(Ok(username)) = file_name.strip_suffix(".cert").map(DocumentId::from_escaped) {
+                certs.push(username.to_string());
             }
         }
         Ok(certs)
     }
 }
 
+/// The identity a certificate was issued to: its common name and, if
+/// present, the first email subject alternative name.
+pub(crate) fn identity(cert: &X509) -> Result<Identity> {
+    let cn = cert.subject_name().entries_by_nid(openssl::nid::Nid::COMMONNAME)
+        .next()
+        .ok_or_else(|| DbError::Certificate("No username found in certificate".to_string()))?;
+    let name = cn.data().as_utf8()
+        .map_err(|e| DbError::Certificate(format!("Invalid username encoding: {}", e)))?
+        .to_string();
+
+    let email = cert.subject_alt_names()
+        .and_then(|names| names.iter().find_map(|name| name.email().map(str::to_string)));
+
+    Ok(Identity { name, email })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{FsBackend, MemoryBackend};
+    use std::fs;
     use tempfile::tempdir;
 
     #[test]
     fn test_certificate_operations() -> Result<()> {
-        let dir = tempdir()?;
-        let cert_manager = CertManager::new(dir.path(), None)?;
+        let cert_manager = CertManager::new(Arc::new(MemoryBackend::new()), None)?;
 
         // Generate certificate
         let username = "testuser";
@@ -164,11 +200,24 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_identity_from_certificate() -> Result<()> {
+        let cert_manager = CertManager::new(Arc::new(MemoryBackend::new()), None)?;
+
+        let (cert, _key) = cert_manager.generate_cert_with_email("alice", Some("alice@example.com"))?;
+        assert_eq!(identity(&X509::from_pem(&cert)?)?, Identity { name: "alice".to_string(), email: Some("alice@example.com".to_string()) });
+
+        let (cert, _key) = cert_manager.generate_cert("bob")?;
+        assert_eq!(identity(&X509::from_pem(&cert)?)?.email, None);
+
+        Ok(())
+    }
+
     #[test]
     fn test_encrypted_certificates() -> Result<()> {
         let dir = tempdir()?;
         let key = [0u8; 32]; // 32-byte key for testing
-        let cert_manager = CertManager::new(dir.path(), Some(&key))?;
+        let cert_manager = CertManager::new(Arc::new(FsBackend::new(dir.path())?), Some(&key))?;
 
         // Generate and verify encrypted certificate
         let username = "testuser";
@@ -182,4 +231,18 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_usernames_cannot_escape_certs_dir() -> Result<()> {
+        let dir = tempdir()?;
+        let cert_manager = CertManager::new(Arc::new(FsBackend::new(dir.path().join("db"))?), None)?;
+
+        let (cert, _key) = cert_manager.generate_cert("../admin")?;
+        assert!(!dir.path().join("db/admin.cert").exists());
+        assert!(cert_manager.verify_cert("../admin", &cert)?);
+        assert_eq!(cert_manager.list_certs()?, vec!["../admin"]);
+        assert!(cert_manager.generate_cert("").is_err());
+
+        Ok(())
+    }
 }
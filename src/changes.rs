@@ -0,0 +1,99 @@
+use crate::{CommitInfo, Document, GitManager};
+use anyhow::Result;
+use git2::{Oid, Sort};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+/// A single document change recorded by a commit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    pub kind: EventKind,
+    /// `None` for documents in the database root.
+    pub collection: Option<String>,
+    pub id: String,
+    pub commit: String,
+    pub author: String,
+    pub timestamp: i64,
+    /// The document before and after the change, if values were requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<Document>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<Document>,
+}
+
+/// A file changed by a commit, as blob ids before and after.
+pub(crate) struct TreeChange {
+    pub path: String,
+    pub old: Option<Oid>,
+    pub new: Option<Oid>,
+}
+
+impl GitManager {
+    /// The tip of the current branch, or `None` before the first commit.
+    pub(crate) fn head_id(&self) -> Result<Option<Oid>> {
+        match self.head() {
+            Ok(head) => Ok(Some(head.peel_to_commit()?.id())),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// The commits after `since` (or all of them) up to `until`, oldest
+    /// first, each with the files it changed relative to its first parent.
+    /// Merged branches show up as the changes of the merge commit.
+    ///
+    /// If `until` doesn't descend from `since`, e.g. because the history was
+    /// compacted or rebased in between, the two trees are compared directly
+    /// and the whole difference is attributed to `until`.
+    pub(crate) fn tree_changes(&self, since: Option<Oid>, until: Oid) -> Result<Vec<(CommitInfo, Vec<TreeChange>)>> {
+        if let Some(since) = since {
+            if since != until && !self.repo.graph_descendant_of(until, since)? {
+                let until = self.repo.find_commit(until)?;
+                let old_tree = self.repo.find_commit(since)?.tree()?;
+                let changes = self.diff_trees(Some(&old_tree), &until.tree()?)?;
+                return Ok(vec![(CommitInfo::from_commit(&until), changes)]);
+            }
+        }
+
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        revwalk.simplify_first_parent()?;
+        revwalk.push(until)?;
+        if let Some(since) = since {
+            revwalk.hide(since)?;
+        }
+
+        let mut commits = Vec::new();
+        for oid in revwalk {
+            let commit = self.repo.find_commit(oid?)?;
+            let parent_tree = match commit.parents().next() {
+                Some(parent) => Some(parent.tree()?),
+                None => None,
+            };
+            let changes = self.diff_trees(parent_tree.as_ref(), &commit.tree()?)?;
+            commits.push((CommitInfo::from_commit(&commit), changes));
+        }
+        Ok(commits)
+    }
+
+    fn diff_trees(&self, old: Option<&git2::Tree>, new: &git2::Tree) -> Result<Vec<TreeChange>> {
+        let diff = self.repo.diff_tree_to_tree(old, Some(new), None)?;
+        Ok(diff.deltas()
+            .filter_map(|delta| {
+                let path = delta.new_file().path().or(delta.old_file().path())?;
+                let blob = |file: git2::DiffFile| Some(file.id()).filter(|id| !id.is_zero());
+                Some(TreeChange {
+                    path: path.to_string_lossy().into_owned(),
+                    old: blob(delta.old_file()),
+                    new: blob(delta.new_file()),
+                })
+            })
+            .collect())
+    }
+}
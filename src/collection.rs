@@ -0,0 +1,308 @@
+use crate::{blame, datakey, BatchOp, DbError, Document, DocumentDiff, FieldBlame, Filter, GithubDb, PurgeReport, Revision, Storage, Transaction};
+use anyhow::Result;
+
+/// A handle to a named collection of documents, or to the default collection
+/// stored in the database root. Every write is committed through the owning
+/// database's git repository.
+pub struct Collection<'a> {
+    pub(crate) db: &'a GithubDb,
+    pub(crate) storage: Storage,
+    name: Option<String>,
+}
+
+impl<'a> Collection<'a> {
+    pub(crate) fn new(db: &'a GithubDb, storage: Storage, name: Option<&str>) -> Self {
+        Self {
+            db,
+            storage,
+            name: name.map(str::to_string),
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The document ID as shown in commit messages, e.g. `users/alice`.
+    pub(crate) fn qualified(&self, id: &str) -> String {
+        match &self.name {
+            Some(name) => format!("{}/{}", name, id),
+            None => id.to_string(),
+        }
+    }
+
+    pub(crate) fn encode(&self, doc: &Document) -> Result<Vec<BatchOp>> {
+        self.db.encode(&self.storage.relative_path(&doc.id)?, doc)
+    }
+
+    /// Writes the document together with its data key, if it gets a new one.
+    fn write(&self, doc: &Document) -> Result<()> {
+        self.storage.backend().write_batch(&self.encode(doc)?)
+    }
+
+    fn decode(&self, id: &str, data: Vec<u8>, rev: Option<&str>) -> Result<Document> {
+        self.db.decode_at(&self.storage.relative_path(id)?, data, rev)
+    }
+
+    pub fn create(&self, id: &str, data: serde_json::Value) -> Result<Document> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+
+        let doc = Document {
+            id: id.to_string(),
+            data,
+            created_at: now,
+            updated_at: now,
+            version: 1,
+        };
+
+        self.write(&doc)?;
+        self.db.commit(&format!("Create document {}", self.qualified(&doc.id)))?;
+
+        Ok(doc)
+    }
+
+    pub fn read(&self, id: &str) -> Result<Document> {
+        self.decode(id, self.storage.read(id)?, None)
+    }
+
+    pub fn read_at(&self, id: &str, rev: &str) -> Result<Document> {
+        self.load(id, Some(rev))?
+            .ok_or_else(|| DbError::Storage(format!("Document {} not found at {}", self.qualified(id), rev)).into())
+    }
+
+    pub(crate) fn load(&self, id: &str, rev: Option<&str>) -> Result<Option<Document>> {
+        let data = match rev {
+            Some(rev) => self.db.git()?.read_file_at(rev, &self.storage.relative_path(id)?)?,
+            None if self.storage.exists(id)? => Some(self.storage.read(id)?),
+            None => None,
+        };
+        data.map(|data| self.decode(id, data, rev)).transpose()
+    }
+
+    /// Compares the document's data between two revisions. `from` defaults to
+    /// HEAD and `to` defaults to the working copy.
+    pub fn diff(&self, id: &str, from: Option<&str>, to: Option<&str>) -> Result<DocumentDiff> {
+        let old = self.load(id, Some(from.unwrap_or("HEAD")))?;
+        let new = self.load(id, to)?;
+        Ok(DocumentDiff::between(
+            old.as_ref().map(|doc| &doc.data),
+            new.as_ref().map(|doc| &doc.data),
+        ))
+    }
+
+    pub fn history(&self, id: &str) -> Result<Vec<Revision>> {
+        self.db.git()?.file_history(&self.storage.relative_path(id)?)?
+            .into_iter()
+            .map(|(commit, data)| {
+                let document = data.map(|data| self.decode(id, data, Some(&commit.id))).transpose()?;
+                Ok(Revision { commit, document })
+            })
+            .collect()
+    }
+
+    /// For every leaf value of the document, the commit that last changed it.
+    pub fn blame(&self, id: &str) -> Result<Vec<FieldBlame>> {
+        blame::blame(&self.history(id)?)
+            .ok_or_else(|| DbError::Storage(format!("Document {} not found", self.qualified(id))).into())
+    }
+
+    pub fn update(&self, id: &str, data: serde_json::Value) -> Result<Document> {
+        self.update_checked(id, None, data)
+    }
+
+    /// Like [`Collection::update`], but fails with [`DbError::Conflict`] if
+    /// the document is no longer at `expected_version`.
+    pub fn update_if(&self, id: &str, expected_version: u64, data: serde_json::Value) -> Result<Document> {
+        self.update_checked(id, Some(expected_version), data)
+    }
+
+    fn update_checked(&self, id: &str, expected_version: Option<u64>, data: serde_json::Value) -> Result<Document> {
+        let mut doc = self.read(id)?;
+        doc.check_version(expected_version)?;
+        doc.data = data;
+        doc.version += 1;
+        doc.updated_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+
+        self.write(&doc)?;
+        self.db.commit(&format!("Update document {}", self.qualified(&doc.id)))?;
+
+        Ok(doc)
+    }
+
+    /// Writes the document as it was at `rev` back as a new commit. Works for
+    /// documents that have since been deleted.
+    pub fn restore(&self, id: &str, rev: &str) -> Result<Document> {
+        let commit_id = self.db.git()?.resolve_commit_id(rev)?;
+        let mut doc = self.read_at(id, &commit_id)?;
+        let current_version = self.load(id, None)?.map(|current| current.version).unwrap_or(0);
+        doc.version = doc.version.max(current_version) + 1;
+        doc.updated_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+
+        self.write(&doc)?;
+        self.db.commit(&format!("Restore document {} to {}", self.qualified(&doc.id), &commit_id[..7]))?;
+
+        Ok(doc)
+    }
+
+    pub fn delete(&self, id: &str) -> Result<()> {
+        self.storage.delete(id)?;
+        self.db.commit(&format!("Delete document {}", self.qualified(id)))?;
+        Ok(())
+    }
+
+    /// Deletes the document and destroys its data key, so that it can no
+    /// longer be decrypted. Needs data keys. Earlier commits still hold the
+    /// key until [`Collection::purge`] removes it from history.
+    pub fn shred(&self, id: &str) -> Result<()> {
+        let key_path = datakey::data_key_path(&self.storage.relative_path(id)?);
+        let backend = self.storage.backend();
+        if !backend.exists(&key_path)? {
+            return Err(DbError::Encryption(format!("Document {} has no data key", self.qualified(id))).into());
+        }
+        if self.storage.exists(id)? {
+            self.storage.delete(id)?;
+        }
+        backend.delete(&key_path)?;
+        self.db.commit(&format!("Shred document {}", self.qualified(id)))
+    }
+
+    /// Removes every data key of the document other than its current one
+    /// from every commit on the current branch, so that revisions written
+    /// before it was shredded can't be decrypted any more. This rewrites
+    /// history and drops the signatures of the rewritten commits; see
+    /// [`GitManager::purge_path`](crate::GitManager::purge_path) for what it
+    /// doesn't reach.
+    pub fn purge(&self, id: &str) -> Result<PurgeReport> {
+        let key_path = datakey::data_key_path(&self.storage.relative_path(id)?);
+        self.db.git()?.purge_path(&key_path)
+    }
+
+    /// Runs `f` against a transaction and commits all of its writes as one
+    /// commit. Nothing is written if `f` returns an error.
+    pub fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Transaction) -> Result<T>,
+    {
+        let mut tx = Transaction::new(self);
+        let result = f(&mut tx)?;
+        tx.commit()?;
+        Ok(result)
+    }
+
+    pub fn list(&self) -> Result<Vec<String>> {
+        self.storage.list()
+    }
+
+    /// The IDs of the documents that existed at `rev`, which may be a
+    /// snapshot name or any other revision.
+    pub fn list_at(&self, rev: &str) -> Result<Vec<String>> {
+        let files = self.db.git()?.list_files_at(rev, self.storage.dir())?;
+        Ok(Storage::document_ids(files))
+    }
+
+    pub fn find(&self, filter: Option<Filter>) -> Result<Vec<Document>> {
+        self.find_in(self.list()?, None, filter)
+    }
+
+    /// Like [`Collection::find`], but searches the documents as they were at
+    /// `rev`.
+    pub fn find_at(&self, filter: Option<Filter>, rev: &str) -> Result<Vec<Document>> {
+        // Pin the revision so every document is read from the same commit
+        let commit_id = self.db.git()?.resolve_commit_id(rev)?;
+        self.find_in(self.list_at(&commit_id)?, Some(&commit_id), filter)
+    }
+
+    fn find_in(&self, ids: Vec<String>, rev: Option<&str>, filter: Option<Filter>) -> Result<Vec<Document>> {
+        let mut results = Vec::new();
+
+        for id in ids {
+            let doc = match rev {
+                Some(rev) => self.read_at(&id, rev)?,
+                None => self.read(&id)?,
+            };
+            if let Some(filter) = &filter {
+                if filter.matches(&doc)? {
+                    results.push(doc);
+                }
+            } else {
+                results.push(doc);
+            }
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_collections_are_isolated() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), None)?;
+
+        db.create("alice", json!({ "kind": "root" }))?;
+        let users = db.collection("users")?;
+        users.create("alice", json!({ "kind": "user" }))?;
+        users.create("bob", json!({ "kind": "user" }))?;
+        db.collection("orders")?.create("o1", json!({ "total": 10 }))?;
+
+        assert!(dir.path().join("collections/users/alice.json").is_file());
+        assert_eq!(db.read("alice")?.data["kind"], "root");
+        assert_eq!(db.list()?, vec!["alice"]);
+
+        let users = db.collection("users")?;
+        assert_eq!(users.read("alice")?.data["kind"], "user");
+        let mut ids = users.list()?;
+        ids.sort();
+        assert_eq!(ids, vec!["alice", "bob"]);
+        assert_eq!(users.find(None)?.len(), 2);
+
+        users.update("bob", json!({ "kind": "admin" }))?;
+        let history = users.history("bob")?;
+        assert_eq!(history[0].commit.message, "Update document users/bob");
+        assert!(db.history("bob")?.is_empty());
+
+        users.transaction(|tx| {
+            tx.delete("alice")?;
+            tx.create("carol", json!({}))?;
+            Ok(())
+        })?;
+        assert!(users.read("alice").is_err());
+        assert!(db.read("alice").is_ok());
+
+        assert_eq!(db.list_collections()?, vec!["orders", "users"]);
+        db.drop_collection("orders")?;
+        assert_eq!(db.list_collections()?, vec!["users"]);
+        assert!(db.collection("orders")?.list()?.is_empty());
+        assert!(db.drop_collection("orders").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_collection_names_are_escaped() -> Result<()> {
+        let dir = tempdir()?;
+        let db = GithubDb::new(dir.path(), None)?;
+
+        assert!(db.collection("").is_err());
+        assert!(db.collection("a\0b").is_err());
+
+        db.collection("..")?.create("x", json!({}))?;
+        db.collection("a/b")?.create("x", json!({}))?;
+        assert!(dir.path().join("collections/%2E./x.json").is_file());
+        assert!(dir.path().join("collections/a%2Fb/x.json").is_file());
+        assert_eq!(db.list_collections()?, vec!["..", "a/b"]);
+
+        Ok(())
+    }
+}
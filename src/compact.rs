@@ -0,0 +1,403 @@
+use crate::{CommitInfo, DbError, GitManager};
+use anyhow::Result;
+use git2::{Oid, Sort};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const BACKUP_REF_PREFIX: &str = "refs/github-db/backup/";
+
+/// Which commits [`GitManager::compact`] keeps. When both limits are set, a
+/// commit has to satisfy both to survive.
+#[derive(Debug, Clone, Default)]
+pub struct CompactOptions {
+    /// Squash every commit older than this into a single base commit.
+    pub max_age: Option<Duration>,
+    /// Keep only the last N revisions of each document.
+    pub keep_revisions: Option<usize>,
+    /// Also rewrite signed commits. Their signatures don't cover the
+    /// rewritten commits, which are signed by the current signer instead, or
+    /// not at all without one.
+    pub rewrite_signed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CompactReport {
+    /// Commits on the branch before and after compaction.
+    pub commits_before: usize,
+    pub commits_after: usize,
+    /// Ref holding the previous tip, or `None` if nothing was rewritten.
+    pub backup_ref: Option<String>,
+    /// Signed commits that were rewritten and lost their signatures.
+    pub lost_signatures: Vec<CommitInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PurgeReport {
+    /// Commits on the current branch that were rewritten.
+    pub rewritten: usize,
+    /// Signed commits among them. They are signed by the current signer
+    /// instead, or not at all without one.
+    pub lost_signatures: Vec<CommitInfo>,
+}
+
+impl GitManager {
+    /// Rewrites the current branch so that it only contains the commits
+    /// selected by `options`. Each kept commit keeps its tree, author and
+    /// message, so the latest state and every kept revision are unchanged;
+    /// dropped commits are folded into the next kept one. The old tip is
+    /// written to a backup ref under `refs/github-db/backup/` first.
+    ///
+    /// Commits before the first dropped one are kept as they are. Every later
+    /// commit is rewritten, which invalidates its signature; unless
+    /// `rewrite_signed` is set, compaction fails instead of rewriting signed
+    /// commits.
+    ///
+    /// History is linearized along first parents. Objects only become
+    /// unreachable; running `git gc` afterwards reclaims the space, once
+    /// the backup ref is deleted. Remotes have to be force-updated.
+    pub fn compact(&self, options: &CompactOptions) -> Result<CompactReport> {
+        if options.max_age.is_none() && options.keep_revisions.is_none() {
+            return Err(DbError::Git("Compaction needs a maximum age or a number of revisions to keep".to_string()).into());
+        }
+        if options.keep_revisions == Some(0) {
+            return Err(DbError::Git("At least one revision per document must be kept".to_string()).into());
+        }
+
+        let head = match self.head() {
+            Ok(head) => head.peel_to_commit()?,
+            Err(_) => return Ok(CompactReport { commits_before: 0, commits_after: 0, backup_ref: None, lost_signatures: Vec::new() }),
+        };
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        revwalk.simplify_first_parent()?;
+        revwalk.push(head.id())?;
+        let commits = revwalk.collect::<Result<Vec<_>, _>>()?;
+
+        let keep = self.commits_to_keep(&commits, options)?;
+        let kept = keep.iter().filter(|keep| **keep).count();
+        let first_dropped = match keep.iter().position(|keep| !keep) {
+            Some(i) => i,
+            None => return Ok(CompactReport {
+                commits_before: commits.len(),
+                commits_after: kept,
+                backup_ref: None,
+                lost_signatures: Vec::new(),
+            }),
+        };
+
+        let mut lost_signatures = Vec::new();
+        for (oid, _) in commits.iter().zip(&keep).skip(first_dropped).filter(|(_, keep)| **keep) {
+            if self.is_signed(*oid)? {
+                lost_signatures.push(CommitInfo::from_commit(&self.repo.find_commit(*oid)?));
+            }
+        }
+        if !lost_signatures.is_empty() && !options.rewrite_signed {
+            return Err(DbError::Git(format!(
+                "Compaction would rewrite {} signed commit(s), invalidating their signatures; allow rewriting signed commits to re-sign them",
+                lost_signatures.len(),
+            )).into());
+        }
+
+        let branch = self.current_branch()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let backup_ref = format!("{}{}/{}", BACKUP_REF_PREFIX, branch, now);
+        self.repo.reference(&backup_ref, head.id(), false, "github-db: backup before compaction")?;
+
+        let committer = self.committer()?;
+        let mut parent = match first_dropped {
+            0 => None,
+            i => Some(self.repo.find_commit(commits[i - 1])?),
+        };
+        let mut squashed = 0;
+        for (oid, keep) in commits.iter().zip(&keep).skip(first_dropped) {
+            if !keep {
+                squashed += 1;
+                continue;
+            }
+            let commit = self.repo.find_commit(*oid)?;
+            let mut message = commit.message().unwrap_or_default().trim_end().to_string();
+            if squashed > 0 {
+                message.push_str(&format!("\n\nCompacted {} earlier commit(s)", squashed));
+                squashed = 0;
+            }
+            let parents: Vec<&git2::Commit> = parent.iter().collect();
+            let id = self.create_commit(None, &commit.author(), &committer, &message, &commit.tree()?, &parents)?;
+            parent = Some(self.repo.find_commit(id)?);
+        }
+
+        let new_tip = parent.map(|commit| commit.id()).unwrap_or(head.id());
+        // Fails if the branch moved while we were rewriting it
+        self.repo.reference_matching(
+            &format!("refs/heads/{}", branch),
+            new_tip,
+            true,
+            head.id(),
+            "github-db: compact history",
+        )?;
+        self.checkout_worktree()?;
+
+        Ok(CompactReport {
+            commits_before: commits.len(),
+            commits_after: kept,
+            backup_ref: Some(backup_ref),
+            lost_signatures,
+        })
+    }
+
+    fn is_signed(&self, oid: Oid) -> Result<bool> {
+        match self.repo.extract_signature(&oid, Some("gpgsig")) {
+            Ok(_) => Ok(true),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Removes every version of `path` except the one at HEAD from every
+    /// commit on the current branch, e.g. to get rid of a destroyed key for
+    /// good. Rewritten commits keep their author and message and merges keep
+    /// their parents; commits that never had such a version are only
+    /// rewritten if one of their ancestors was. Rewritten commits lose their
+    /// signatures, which the report lists. Unlike compaction no backup ref is
+    /// kept, as it would keep the old versions reachable.
+    ///
+    /// Other branches, tags, snapshots, the reflog, remotes and clones still
+    /// hold the old commits; the old versions are only gone once those are
+    /// rewritten or dropped as well and `git gc` has pruned the objects.
+    pub fn purge_path(&self, path: &str) -> Result<PurgeReport> {
+        let mut report = PurgeReport { rewritten: 0, lost_signatures: Vec::new() };
+        let head = match self.head() {
+            Ok(head) => head.peel_to_commit()?,
+            Err(_) => return Ok(report),
+        };
+        let current = head.tree()?.get_path(Path::new(path)).ok().map(|entry| entry.id());
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        revwalk.push(head.id())?;
+
+        let committer = self.committer()?;
+        let mut rewritten: HashMap<Oid, Oid> = HashMap::new();
+        for oid in revwalk {
+            let oid = oid?;
+            let commit = self.repo.find_commit(oid)?;
+            let mut tree = commit.tree()?;
+            if tree.get_path(Path::new(path)).is_ok_and(|entry| Some(entry.id()) != current) {
+                let mut index = git2::Index::new()?;
+                index.read_tree(&tree)?;
+                index.remove_path(Path::new(path))?;
+                tree = self.repo.find_tree(index.write_tree_to(&self.repo)?)?;
+            }
+            let parent_ids: Vec<Oid> = commit.parent_ids()
+                .map(|id| rewritten.get(&id).copied().unwrap_or(id))
+                .collect();
+            if tree.id() == commit.tree_id() && parent_ids.iter().copied().eq(commit.parent_ids()) {
+                continue;
+            }
+
+            if self.is_signed(oid)? {
+                report.lost_signatures.push(CommitInfo::from_commit(&commit));
+            }
+            let parents = parent_ids.iter()
+                .map(|id| self.repo.find_commit(*id))
+                .collect::<Result<Vec<_>, _>>()?;
+            let parents: Vec<&git2::Commit> = parents.iter().collect();
+            let message = commit.message().unwrap_or_default();
+            let id = self.create_commit(None, &commit.author(), &committer, message, &tree, &parents)?;
+            rewritten.insert(oid, id);
+        }
+
+        let new_tip = match rewritten.get(&head.id()) {
+            Some(id) => *id,
+            None => return Ok(report),
+        };
+        self.repo.reference_matching(
+            &format!("refs/heads/{}", self.current_branch()?),
+            new_tip,
+            true,
+            head.id(),
+            "github-db: purge path",
+        )?;
+        self.checkout_worktree()?;
+        report.rewritten = rewritten.len();
+        Ok(report)
+    }
+
+    /// For each commit (oldest first), whether it survives compaction. The
+    /// tip is always kept so the branch's contents don't change.
+    fn commits_to_keep(&self, commits: &[Oid], options: &CompactOptions) -> Result<Vec<bool>> {
+        let mut keep = vec![true; commits.len()];
+
+        if let Some(max_age) = options.max_age {
+            let cutoff = SystemTime::now().duration_since(UNIX_EPOCH)?.saturating_sub(max_age).as_secs() as i64;
+            let old: Vec<bool> = commits.iter()
+                .map(|oid| Ok(self.repo.find_commit(*oid)?.time().seconds() < cutoff))
+                .collect::<Result<_>>()?;
+            // The newest old commit becomes the base holding the squashed state
+            let base = old.iter().rposition(|old| *old);
+            for (i, old) in old.iter().enumerate() {
+                if *old && Some(i) != base {
+                    keep[i] = false;
+                }
+            }
+        }
+
+        if let Some(limit) = options.keep_revisions {
+            let mut needed = vec![false; commits.len()];
+            let mut revisions: HashMap<String, usize> = HashMap::new();
+            for (i, oid) in commits.iter().enumerate().rev() {
+                let commit = self.repo.find_commit(*oid)?;
+                let parent_tree = match commit.parents().next() {
+                    Some(parent) => Some(parent.tree()?),
+                    None => None,
+                };
+                let diff = self.repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+                for delta in diff.deltas() {
+                    if let Some(path) = delta.new_file().path().or(delta.old_file().path()) {
+                        let count = revisions.entry(path.to_string_lossy().into_owned()).or_default();
+                        *count += 1;
+                        if *count <= limit {
+                            needed[i] = true;
+                        }
+                    }
+                }
+            }
+            for (keep, needed) in keep.iter_mut().zip(needed) {
+                *keep &= needed;
+            }
+        }
+
+        if let Some(tip) = keep.last_mut() {
+            *tip = true;
+        }
+        Ok(keep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use crate::cert::CertManager;
+    use crate::CommitSigner;
+    use std::fs;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_keep_revisions_per_file() -> Result<()> {
+        let dir = tempdir()?;
+        let git = GitManager::new(dir.path())?;
+        fs::write(dir.path().join("stable.json"), "s")?;
+        git.commit("stable")?;
+        for i in 0..5 {
+            fs::write(dir.path().join("hot.json"), i.to_string())?;
+            git.commit(&format!("hot {}", i))?;
+        }
+
+        let report = git.compact(&CompactOptions { keep_revisions: Some(2), ..Default::default() })?;
+        assert_eq!((report.commits_before, report.commits_after), (6, 3));
+
+        let history: Vec<_> = git.file_history("hot.json")?.into_iter().map(|(c, _)| c.message).collect();
+        assert_eq!(history, vec!["hot 4", "hot 3\n\nCompacted 3 earlier commit(s)"]);
+        assert_eq!(git.file_history("stable.json")?[0].0.message, "stable");
+        assert_eq!(git.read_file_at("HEAD", "hot.json")?.as_deref(), Some(&b"4"[..]));
+
+        // The old history is still reachable through the backup ref
+        let backup = report.backup_ref.unwrap();
+        assert_eq!(git.read_file_at(&format!("{}~4", backup), "hot.json")?.as_deref(), Some(&b"0"[..]));
+
+        // Nothing left to drop
+        let report = git.compact(&CompactOptions { keep_revisions: Some(2), ..Default::default() })?;
+        assert_eq!(report.backup_ref, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_squash_by_age() -> Result<()> {
+        let dir = tempdir()?;
+        let git = GitManager::new(dir.path())?;
+        for i in 0..3 {
+            fs::write(dir.path().join("a.json"), i.to_string())?;
+            git.commit(&format!("write {}", i))?;
+        }
+
+        // Everything is older than zero seconds: squashed into the tip
+        std::thread::sleep(Duration::from_millis(1100));
+        let report = git.compact(&CompactOptions { max_age: Some(Duration::ZERO), ..Default::default() })?;
+        assert_eq!(report.commits_after, 1);
+        let history = git.file_history("a.json")?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].0.message, "write 2\n\nCompacted 2 earlier commit(s)");
+
+        assert!(git.compact(&CompactOptions::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_signed_commits_need_opt_in() -> Result<()> {
+        let dir = tempdir()?;
+        let mut git = GitManager::new(dir.path())?;
+        let certs = CertManager::new(Arc::new(MemoryBackend::new()), None)?;
+        let (cert, key) = certs.generate_cert("alice")?;
+        git.set_signer(Some(CommitSigner::new(&cert, &key)?));
+        fs::write(dir.path().join("stable.json"), "s")?;
+        git.commit("stable")?;
+        for i in 0..3 {
+            fs::write(dir.path().join("hot.json"), i.to_string())?;
+            git.commit(&format!("hot {}", i))?;
+        }
+        let stable = git.resolve_commit_id("HEAD~3")?;
+
+        let mut options = CompactOptions { keep_revisions: Some(1), ..Default::default() };
+        assert!(git.compact(&options).is_err());
+        assert_eq!(git.file_history("hot.json")?.len(), 3);
+
+        options.rewrite_signed = true;
+        git.set_signer(None);
+        let report = git.compact(&options)?;
+        let lost: Vec<_> = report.lost_signatures.iter().map(|c| c.message.as_str()).collect();
+        assert_eq!(lost, vec!["hot 2"]);
+        // The commit before the first dropped one is untouched
+        assert_eq!(git.resolve_commit_id("HEAD~1")?, stable);
+        assert!(git.is_signed(git.resolve("HEAD~1")?.id())?);
+        assert!(!git.is_signed(git.resolve("HEAD")?.id())?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_purge_path() -> Result<()> {
+        let dir = tempdir()?;
+        let git = GitManager::new(dir.path())?;
+        fs::write(dir.path().join("a.json"), "a")?;
+        git.commit("add a")?;
+        fs::create_dir(dir.path().join("keys"))?;
+        fs::write(dir.path().join("keys/secret"), "1")?;
+        git.commit("add secret")?;
+        fs::write(dir.path().join("keys/secret"), "2")?;
+        fs::write(dir.path().join("a.json"), "b")?;
+        git.commit("change both")?;
+
+        // The current version stays
+        assert_eq!(git.purge_path("keys/secret")?.rewritten, 2);
+        assert_eq!(git.read_file_at("HEAD", "keys/secret")?.as_deref(), Some(&b"2"[..]));
+        assert_eq!(git.read_file_at("HEAD~1", "keys/secret")?, None);
+
+        fs::remove_file(dir.path().join("keys/secret"))?;
+        git.commit("remove secret")?;
+        let report = git.purge_path("keys/secret")?;
+        assert_eq!(report.rewritten, 2);
+        assert!(report.lost_signatures.is_empty());
+        let history = git.file_history("a.json")?;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].0.message, "change both");
+        for rev in ["HEAD", "HEAD~1", "HEAD~2", "HEAD~3"] {
+            assert_eq!(git.read_file_at(rev, "keys/secret")?, None);
+        }
+        assert_eq!(git.read_file_at("HEAD", "a.json")?.as_deref(), Some(&b"b"[..]));
+
+        assert_eq!(git.purge_path("keys/secret")?.rewritten, 0);
+        Ok(())
+    }
+}
@@ -1,54 +1,326 @@
 use aes_gcm::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
     Aes256Gcm, Key, Nonce,
 };
 use crate::DbError;
 use anyhow::Result;
 use rand::Rng;
+use std::fmt;
 
-pub struct Crypto {
+/// Marks data written in the envelope format. Data without it is either
+/// plaintext or legacy `nonce || ciphertext` from before the envelope.
+const MAGIC: &[u8; 4] = b"GHDB";
+/// Version 2 authenticates a context, the file's path, along with the
+/// header; version 1 only the header.
+const VERSION: u8 = 2;
+const VERSION_1: u8 = 1;
+const ALGORITHM_AES_256_GCM: u8 = 1;
+/// AES-256-GCM under a per-file data key rather than the master key. The key
+/// id names the data key, which is stored separately, wrapped by the master
+/// key.
+const ALGORITHM_AES_256_GCM_DATA_KEY: u8 = 2;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 2 + 8 + 8;
+
+/// Identifies a key without revealing it: the first 8 bytes of a SHA-256
+/// over the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyId(pub [u8; 8]);
+
+impl KeyId {
+    pub fn of(key: &[u8]) -> Self {
+        let mut hasher = openssl::sha::Sha256::new();
+        hasher.update(b"github-db key id\0");
+        hasher.update(key);
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&hasher.finish()[..8]);
+        KeyId(id)
+    }
+}
+
+impl fmt::Display for KeyId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// The self-describing header in front of every encrypted file:
+///
+/// ```text
+/// "GHDB" | version (1) | algorithm (1) | key id (8) | KDF id (8) | nonce (12) | ciphertext + tag
+/// ```
+///
+/// The header and the context given to [`Crypto::encrypt_for`] are
+/// authenticated along with the ciphertext.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Envelope {
+    pub version: u8,
+    pub algorithm: u8,
+    /// The key the data was encrypted with.
+    pub key_id: KeyId,
+    /// The key derivation parameters that key was derived with; all zeros for
+    /// a raw key.
+    pub kdf_id: [u8; 8],
+}
+
+impl Envelope {
+    /// Parses the header of `data`, or returns `None` if `data` isn't in the
+    /// envelope format.
+    pub fn parse(data: &[u8]) -> Result<Option<Self>> {
+        if !data.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        if data.len() < HEADER_LEN + NONCE_LEN {
+            return Err(DbError::Encryption("Truncated encrypted data".to_string()).into());
+        }
+        let mut key_id = [0u8; 8];
+        key_id.copy_from_slice(&data[6..14]);
+        let mut kdf_id = [0u8; 8];
+        kdf_id.copy_from_slice(&data[14..22]);
+        Ok(Some(Self { version: data[4], algorithm: data[5], key_id: KeyId(key_id), kdf_id }))
+    }
+
+    /// Whether the data is encrypted with a data key instead of the master
+    /// key; see [`Crypto::encrypt_with_data_key`].
+    pub fn uses_data_key(&self) -> bool {
+        self.algorithm == ALGORITHM_AES_256_GCM_DATA_KEY
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut header = [0u8; HEADER_LEN];
+        header[..4].copy_from_slice(MAGIC);
+        header[4] = self.version;
+        header[5] = self.algorithm;
+        header[6..14].copy_from_slice(&self.key_id.0);
+        header[14..22].copy_from_slice(&self.kdf_id);
+        header
+    }
+}
+
+#[derive(Clone)]
+struct KeyEntry {
+    id: KeyId,
+    kdf_id: [u8; 8],
     cipher: Aes256Gcm,
 }
 
-impl Crypto {
-    pub fn new(key: &[u8]) -> Result<Self> {
+impl KeyEntry {
+    fn new(key: &[u8], kdf_id: [u8; 8]) -> Result<Self> {
         if key.len() != 32 {
             return Err(DbError::Encryption("Key must be exactly 32 bytes".to_string()).into());
         }
-        let key = Key::<Aes256Gcm>::from_slice(key);
-        let cipher = Aes256Gcm::new(key);
-        Ok(Self { cipher })
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+        Ok(Self { id: KeyId::of(key), kdf_id, cipher })
+    }
+}
+
+/// Encrypts with one key and decrypts with any of the keys it holds, picked
+/// by the key id in the envelope. Extra keys let a database whose files are
+/// encrypted with different keys, e.g. halfway through a rotation, be read.
+#[derive(Clone)]
+pub struct Crypto {
+    primary: KeyEntry,
+    others: Vec<KeyEntry>,
+    /// Whether formats that aren't bound to a context are decrypted.
+    legacy: bool,
+}
+
+impl Crypto {
+    pub fn new(key: &[u8]) -> Result<Self> {
+        Self::with_kdf_id(key, [0u8; 8])
+    }
+
+    /// Like [`Crypto::new`] for a key derived with the key derivation
+    /// parameters identified by `kdf_id`, which is recorded in each envelope.
+    pub fn with_kdf_id(key: &[u8], kdf_id: [u8; 8]) -> Result<Self> {
+        Ok(Self { primary: KeyEntry::new(key, kdf_id)?, others: Vec::new(), legacy: false })
+    }
+
+    /// Also decrypt the formats that don't authenticate a context: bare
+    /// legacy ciphertext and version 1 envelopes. Anyone who can write to the
+    /// repository can put such data, taken from another path or revision, in
+    /// place of a file, so this is only for upgrading old databases and
+    /// reading their history.
+    pub fn allow_legacy(&mut self) {
+        self.legacy = true;
+    }
+
+    /// Also decrypt data encrypted with `key`.
+    pub fn add_key(&mut self, key: &[u8]) -> Result<()> {
+        let entry = KeyEntry::new(key, [0u8; 8])?;
+        if entry.id != self.primary.id && self.others.iter().all(|other| other.id != entry.id) {
+            self.others.push(entry);
+        }
+        Ok(())
     }
 
-    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let mut rng = rand::thread_rng();
-        let mut nonce_bytes = [0u8; 12];
-        rng.fill(&mut nonce_bytes);
-        let nonce = Nonce::from_slice(&nonce_bytes);
+    /// The id of the key data is encrypted with.
+    pub fn key_id(&self) -> KeyId {
+        self.primary.id
+    }
 
-        let ciphertext = self.cipher
-            .encrypt(nonce, data)
-            .map_err(|e| DbError::Encryption(e.to_string()))?;
-            
-        let mut result = Vec::with_capacity(nonce_bytes.len() + ciphertext.len());
-        result.extend_from_slice(&nonce_bytes);
-        result.extend_from_slice(&ciphertext);
+    /// Whether `data` is in the envelope format, as opposed to plaintext or
+    /// legacy ciphertext.
+    pub fn is_encrypted(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
 
-        Ok(result)
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.encrypt_for(data, b"")
     }
 
     pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
-        if data.len() < 12 {
+        self.decrypt_for(data, b"")
+    }
+
+    /// Encrypts `data` so that it only decrypts with the same `context`,
+    /// typically the path it is stored at. Copying the result to another
+    /// path makes it fail authentication.
+    pub fn encrypt_for(&self, data: &[u8], context: &[u8]) -> Result<Vec<u8>> {
+        seal(&self.primary, ALGORITHM_AES_256_GCM, data, context)
+    }
+
+    /// Decrypts data written by [`Crypto::encrypt_for`] with the same
+    /// `context`. Data from before contexts were used is only accepted after
+    /// [`Crypto::allow_legacy`].
+    pub fn decrypt_for(&self, data: &[u8], context: &[u8]) -> Result<Vec<u8>> {
+        let envelope = match Envelope::parse(data)? {
+            Some(envelope) => envelope,
+            None if self.legacy => return self.decrypt_legacy(data),
+            None => return Err(legacy_format(context).into()),
+        };
+        if envelope.uses_data_key() {
+            return Err(DbError::Encryption(format!("{} is encrypted with a data key", describe(context))).into());
+        }
+        if envelope.algorithm != ALGORITHM_AES_256_GCM {
+            return Err(DbError::Encryption(format!("Unsupported encryption algorithm {}", envelope.algorithm)).into());
+        }
+
+        let entry = std::iter::once(&self.primary)
+            .chain(&self.others)
+            .find(|entry| entry.id == envelope.key_id)
+            .ok_or_else(|| DbError::WrongKey {
+                expected: envelope.key_id.to_string(),
+                actual: self.primary.id.to_string(),
+            })?;
+        open(entry, &envelope, data, context, self.legacy)
+    }
+
+    /// A fresh random key for [`Crypto::encrypt_with_data_key`].
+    pub fn generate_data_key() -> [u8; 32] {
+        rand::thread_rng().gen()
+    }
+
+    /// Encrypts a data key with the master key, bound to `context`, the path
+    /// the wrapped key is stored at.
+    pub fn wrap_key(&self, data_key: &[u8], context: &[u8]) -> Result<Vec<u8>> {
+        self.encrypt_for(data_key, context)
+    }
+
+    /// The inverse of [`Crypto::wrap_key`].
+    pub fn unwrap_key(&self, wrapped: &[u8], context: &[u8]) -> Result<Vec<u8>> {
+        self.decrypt_for(wrapped, context)
+    }
+
+    /// Encrypts `data` with a data key instead of the master key, so that
+    /// rotating the master key only means rewrapping the data key.
+    pub fn encrypt_with_data_key(data_key: &[u8], data: &[u8], context: &[u8]) -> Result<Vec<u8>> {
+        seal(&KeyEntry::new(data_key, [0u8; 8])?, ALGORITHM_AES_256_GCM_DATA_KEY, data, context)
+    }
+
+    /// Decrypts data written by [`Crypto::encrypt_with_data_key`].
+    pub fn decrypt_with_data_key(data_key: &[u8], data: &[u8], context: &[u8]) -> Result<Vec<u8>> {
+        let envelope = Envelope::parse(data)?
+            .filter(Envelope::uses_data_key)
+            .ok_or_else(|| DbError::Encryption(format!("{} is not encrypted with a data key", describe(context))))?;
+        let entry = KeyEntry::new(data_key, [0u8; 8])?;
+        if entry.id != envelope.key_id {
+            return Err(DbError::WrongKey {
+                expected: envelope.key_id.to_string(),
+                actual: entry.id.to_string(),
+            }.into());
+        }
+        open(&entry, &envelope, data, context, false)
+    }
+
+    /// Bare `nonce || ciphertext` as written before the envelope format. It
+    /// doesn't say which key it needs, so every key is tried.
+    fn decrypt_legacy(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < NONCE_LEN {
             return Err(DbError::Encryption("Invalid encrypted data".to_string()).into());
         }
 
-        let (nonce_bytes, ciphertext) = data.split_at(12);
+        let (nonce_bytes, ciphertext) = data.split_at(NONCE_LEN);
         let nonce = Nonce::from_slice(nonce_bytes);
-        let plaintext = self.cipher
-            .decrypt(nonce, ciphertext)
-            .map_err(|e| DbError::Encryption(e.to_string()))?;
+        std::iter::once(&self.primary)
+            .chain(&self.others)
+            .find_map(|entry| entry.cipher.decrypt(nonce, ciphertext).ok())
+            .ok_or_else(|| DbError::Encryption(
+                "Data is not encrypted with this key (or at all)".to_string()
+            ).into())
+    }
+}
+
+fn seal(entry: &KeyEntry, algorithm: u8, data: &[u8], context: &[u8]) -> Result<Vec<u8>> {
+    let mut rng = rand::thread_rng();
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    rng.fill(&mut nonce_bytes);
+    let nonce = Nonce::from_slice(&nonce_bytes);
+
+    let header = Envelope {
+        version: VERSION,
+        algorithm,
+        key_id: entry.id,
+        kdf_id: entry.kdf_id,
+    }.to_bytes();
+    let aad = [&header[..], context].concat();
+    let ciphertext = entry.cipher
+        .encrypt(nonce, Payload { msg: data, aad: &aad })
+        .map_err(|e| DbError::Encryption(e.to_string()))?;
+
+    let mut result = Vec::with_capacity(header.len() + nonce_bytes.len() + ciphertext.len());
+    result.extend_from_slice(&header);
+    result.extend_from_slice(&nonce_bytes);
+    result.extend_from_slice(&ciphertext);
+
+    Ok(result)
+}
+
+/// Decrypts an envelope; version 1 only if `legacy` is set.
+fn open(entry: &KeyEntry, envelope: &Envelope, data: &[u8], context: &[u8], legacy: bool) -> Result<Vec<u8>> {
+    let (header, rest) = data.split_at(HEADER_LEN);
+    let (nonce_bytes, ciphertext) = rest.split_at(NONCE_LEN);
+    let nonce = Nonce::from_slice(nonce_bytes);
+    let aad = match envelope.version {
+        VERSION => [header, context].concat(),
+        VERSION_1 if legacy => header.to_vec(),
+        VERSION_1 => return Err(legacy_format(context).into()),
+        version => return Err(DbError::Encryption(format!("Unsupported encryption format version {}", version)).into()),
+    };
+    entry.cipher
+        .decrypt(nonce, Payload { msg: ciphertext, aad: &aad })
+        .map_err(|_| DbError::Tampered(format!(
+            "{} failed authentication: it was modified or copied from elsewhere",
+            describe(context),
+        )).into())
+}
+
+fn legacy_format(context: &[u8]) -> DbError {
+    DbError::Encryption(format!(
+        "{} is in a legacy encryption format that isn't bound to its path. Rerun with \
+         --allow-legacy-encryption to read it, and rotate the key once with it to upgrade the database",
+        describe(context),
+    ))
+}
 
-        Ok(plaintext)
+fn describe(context: &[u8]) -> String {
+    if context.is_empty() {
+        "encrypted data".to_string()
+    } else {
+        String::from_utf8_lossy(context).into_owned()
     }
 }
 
@@ -74,4 +346,102 @@ mod tests {
         let key = [0u8; 16]; // Wrong key length
         assert!(Crypto::new(&key).is_err());
     }
+
+    #[test]
+    fn test_envelope() -> Result<()> {
+        let crypto = Crypto::with_kdf_id(&[1u8; 32], [9u8; 8])?;
+        let encrypted = crypto.encrypt(b"{}")?;
+        assert!(Crypto::is_encrypted(&encrypted));
+        assert!(!Crypto::is_encrypted(b"{}"));
+
+        let envelope = Envelope::parse(&encrypted)?.unwrap();
+        assert_eq!(envelope.key_id, crypto.key_id());
+        assert_eq!(envelope.kdf_id, [9u8; 8]);
+
+        // The wrong key is named as such
+        let other = Crypto::new(&[2u8; 32])?;
+        let err = other.decrypt(&encrypted).unwrap_err().downcast::<DbError>()?;
+        assert!(matches!(err, DbError::WrongKey { .. }));
+
+        // Unless it has the right key as well
+        let mut both = other.clone();
+        both.add_key(&[1u8; 32])?;
+        assert_eq!(both.decrypt(&encrypted)?, b"{}");
+
+        // The header is authenticated
+        let mut tampered = encrypted.clone();
+        tampered[14] ^= 1;
+        assert!(crypto.decrypt(&tampered).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_context_is_authenticated() -> Result<()> {
+        let crypto = Crypto::new(&[1u8; 32])?;
+        let encrypted = crypto.encrypt_for(b"{}", b"a.json")?;
+        assert_eq!(crypto.decrypt_for(&encrypted, b"a.json")?, b"{}");
+
+        let err = crypto.decrypt_for(&encrypted, b"b.json").unwrap_err().downcast::<DbError>()?;
+        assert!(matches!(err, DbError::Tampered(_)));
+
+        // Version 1 envelopes carry no context
+        let mut v1 = crypto.encrypt(b"{}")?;
+        v1[4] = 1;
+        let header = v1[..HEADER_LEN].to_vec();
+        let (nonce, _) = v1[HEADER_LEN..].split_at(NONCE_LEN);
+        let nonce = nonce.to_vec();
+        let ciphertext = crypto.primary.cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: b"{}", aad: &header })
+            .unwrap();
+        v1.truncate(HEADER_LEN + NONCE_LEN);
+        v1.extend(ciphertext);
+        // so they are refused unless legacy formats are allowed
+        let err = crypto.decrypt_for(&v1, b"a.json").unwrap_err().to_string();
+        assert!(err.contains("legacy"), "{}", err);
+        let mut legacy = crypto.clone();
+        legacy.allow_legacy();
+        assert_eq!(legacy.decrypt_for(&v1, b"b.json")?, b"{}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_keys() -> Result<()> {
+        let master = Crypto::new(&[1u8; 32])?;
+        let data_key = Crypto::generate_data_key();
+        let wrapped = master.wrap_key(&data_key, b"keys/data/a.json.key")?;
+        assert_eq!(master.unwrap_key(&wrapped, b"keys/data/a.json.key")?, data_key);
+        assert!(master.unwrap_key(&wrapped, b"keys/data/b.json.key").is_err());
+
+        let encrypted = Crypto::encrypt_with_data_key(&data_key, b"{}", b"a.json")?;
+        let envelope = Envelope::parse(&encrypted)?.unwrap();
+        assert!(envelope.uses_data_key());
+        assert_eq!(envelope.key_id, KeyId::of(&data_key));
+        assert_eq!(Crypto::decrypt_with_data_key(&data_key, &encrypted, b"a.json")?, b"{}");
+
+        let err = Crypto::decrypt_with_data_key(&[2u8; 32], &encrypted, b"a.json").unwrap_err().downcast::<DbError>()?;
+        assert!(matches!(err, DbError::WrongKey { .. }));
+        assert!(Crypto::decrypt_with_data_key(&data_key, &encrypted, b"b.json").is_err());
+        // The master key alone can't read it
+        assert!(master.decrypt_for(&encrypted, b"a.json").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reads_legacy_layout() -> Result<()> {
+        let key = [3u8; 32];
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+        let nonce = [5u8; NONCE_LEN];
+        let mut legacy = nonce.to_vec();
+        legacy.extend(cipher.encrypt(Nonce::from_slice(&nonce), &b"old"[..]).unwrap());
+
+        assert!(!Crypto::is_encrypted(&legacy));
+        assert!(Crypto::new(&key)?.decrypt(&legacy).is_err());
+        let mut crypto = Crypto::new(&key)?;
+        crypto.allow_legacy();
+        assert_eq!(crypto.decrypt(&legacy)?, b"old");
+        let mut other = Crypto::new(&[4u8; 32])?;
+        other.allow_legacy();
+        assert!(other.decrypt(&legacy).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,298 @@
+use crate::backend::BatchOp;
+use crate::merge::MergeCommits;
+use crate::{Crypto, DbError, GithubDb, KeyId, StorageBackend};
+use anyhow::Result;
+use git2::Oid;
+use serde::{Deserialize, Serialize};
+
+/// Wrapped data keys, stored under the path of the document they encrypt.
+pub(crate) const DATA_KEYS_DIR: &str = "keys/data";
+const CONFIG_PATH: &str = "keys/config";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KeyConfig {
+    #[serde(default)]
+    data_keys: bool,
+}
+
+/// Where the wrapped data key of the document at `path` is stored, e.g.
+/// `keys/data/collections/users/alice.json.key`.
+pub(crate) fn data_key_path(path: &str) -> String {
+    format!("{}/{}.key", DATA_KEYS_DIR, path)
+}
+
+/// Every wrapped data key in the database.
+pub(crate) fn data_key_paths(backend: &dyn StorageBackend) -> Result<Vec<String>> {
+    fn walk(backend: &dyn StorageBackend, dir: &str, paths: &mut Vec<String>) -> Result<()> {
+        for file_name in backend.list(dir)? {
+            paths.push(format!("{}/{}", dir, file_name));
+        }
+        for name in backend.list_dirs(dir)? {
+            walk(backend, &format!("{}/{}", dir, name), paths)?;
+        }
+        Ok(())
+    }
+    let mut paths = Vec::new();
+    walk(backend, DATA_KEYS_DIR, &mut paths)?;
+    Ok(paths)
+}
+
+impl GithubDb {
+    /// Whether documents are encrypted with per-document data keys.
+    pub fn data_keys_enabled(&self) -> Result<bool> {
+        let backend = self.storage.backend();
+        if !backend.exists(CONFIG_PATH)? {
+            return Ok(false);
+        }
+        let config: KeyConfig = serde_json::from_slice(&backend.read(CONFIG_PATH)?)
+            .map_err(|e| DbError::Encryption(format!("Invalid key configuration: {}", e)))?;
+        Ok(config.data_keys)
+    }
+
+    /// Switches the database to per-document data keys. Each document is
+    /// encrypted with its own random key, which is stored wrapped by the
+    /// encryption key under `keys/data/`. Rotating the encryption key then
+    /// only rewraps the data keys, and [`Collection::shred`] can make a
+    /// single document unreadable by destroying its key.
+    ///
+    /// Existing documents are converted in the same commit; returns how many.
+    ///
+    /// [`Collection::shred`]: crate::Collection::shred
+    pub fn enable_data_keys(&mut self) -> Result<usize> {
+        let crypto = self.crypto.as_ref()
+            .ok_or_else(|| DbError::Encryption("Data keys need an encryption key".to_string()))?;
+        let backend = self.storage.backend().clone();
+
+        // The configuration, the keys and the converted documents are written
+        // in one batch, so a failure leaves the database as it was
+        let mut ops: Vec<BatchOp> = vec![
+            (CONFIG_PATH.to_string(), Some(serde_json::to_string_pretty(&KeyConfig { data_keys: true })?.into_bytes())),
+        ];
+        let mut converted = 0;
+        for path in self.document_paths()? {
+            let data = backend.read(&path)?;
+            if crate::Envelope::parse(&data)?.is_some_and(|envelope| envelope.uses_data_key()) {
+                continue;
+            }
+            let doc = self.decode(&path, data)?;
+            let (data_key, new_key) = self.data_key_for_write(crypto, &path)?;
+            ops.extend(new_key);
+            ops.push((path.clone(), Some(self.encode_with(&path, &doc, Some(&data_key))?)));
+            converted += 1;
+        }
+        backend.write_batch(&ops)?;
+        self.commit("Enable per-document data keys")?;
+        Ok(converted)
+    }
+
+    /// The data key for writing the document at `path`, created if the
+    /// document doesn't have one yet. A document keeps its key across
+    /// updates, deletes and re-creation, until it is shredded.
+    ///
+    /// A new key comes with the write storing it, which the caller puts in
+    /// the same batch as the document.
+    pub(crate) fn data_key_for_write(&self, crypto: &Crypto, path: &str) -> Result<(Vec<u8>, Option<BatchOp>)> {
+        let key_path = data_key_path(path);
+        let backend = self.storage.backend();
+        if backend.exists(&key_path)? {
+            return Ok((crypto.unwrap_key(&backend.read(&key_path)?, key_path.as_bytes())?, None));
+        }
+        let data_key = Crypto::generate_data_key();
+        let wrapped = crypto.wrap_key(&data_key, key_path.as_bytes())?;
+        Ok((data_key.to_vec(), Some((key_path, Some(wrapped)))))
+    }
+
+    /// The data key `key_id` of the document at `path`. Looked up in the
+    /// current state first and, for documents read at `rev`, in that
+    /// revision, since the key may since have been shredded and replaced.
+    pub(crate) fn data_key(&self, crypto: &Crypto, path: &str, key_id: KeyId, rev: Option<&str>) -> Result<Vec<u8>> {
+        let key_path = data_key_path(path);
+        let backend = self.storage.backend();
+        let current = if backend.exists(&key_path)? { Some(backend.read(&key_path)?) } else { None };
+        let at_rev = match (&self.git, rev) {
+            (Some(git), Some(rev)) => git.read_file_at(rev, &key_path)?,
+            _ => None,
+        };
+        for wrapped in current.into_iter().chain(at_rev) {
+            let data_key = crypto.unwrap_key(&wrapped, key_path.as_bytes())?;
+            if KeyId::of(&data_key) == key_id {
+                return Ok(data_key);
+            }
+        }
+        Err(DbError::Encryption(format!("The data key of {} has been destroyed", path)).into())
+    }
+
+    /// The data key the document at `path` has once `commits` are merged:
+    /// the key file is merged like any other, so a key replaced on one side
+    /// wins. `None` if neither side has a data key for it.
+    pub(crate) fn merged_data_key(&self, crypto: &Crypto, path: &str, commits: &MergeCommits) -> Result<Option<Vec<u8>>> {
+        let key_path = data_key_path(path);
+        let git = self.git()?;
+        let read = |commit: Oid| git.read_file_at(&commit.to_string(), &key_path);
+        let base = commits.base.map(read).transpose()?.flatten();
+        let ours = read(commits.ours)?;
+        let wrapped = if ours == base { read(commits.theirs)? } else { ours };
+        wrapped.map(|wrapped| crypto.unwrap_key(&wrapped, key_path.as_bytes())).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_enable_data_keys() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        db.create("a", json!({ "n": 1 }))?;
+        db.collection("users")?.create("b", json!({ "n": 2 }))?;
+        assert!(!db.data_keys_enabled()?);
+
+        assert_eq!(db.enable_data_keys()?, 2);
+        assert_eq!(db.enable_data_keys()?, 0);
+        assert!(db.data_keys_enabled()?);
+        assert!(dir.path().join("keys/data/a.json.key").is_file());
+        assert!(dir.path().join("keys/data/collections/users/b.json.key").is_file());
+        assert_eq!(data_key_paths(db.storage.backend().as_ref())?.len(), 2);
+
+        let stored = std::fs::read(dir.path().join("a.json"))?;
+        assert!(crate::Envelope::parse(&stored)?.unwrap().uses_data_key());
+        assert_eq!(db.read("a")?.data, json!({ "n": 1 }));
+        // Old revisions under the encryption key stay readable
+        assert_eq!(db.read_at("a", "HEAD~1")?.data, json!({ "n": 1 }));
+
+        // Updates keep the document's data key
+        let key = std::fs::read(dir.path().join("keys/data/a.json.key"))?;
+        db.update("a", json!({ "n": 3 }))?;
+        assert_eq!(std::fs::read(dir.path().join("keys/data/a.json.key"))?, key);
+        db.create("c", json!({}))?;
+        assert!(dir.path().join("keys/data/c.json.key").is_file());
+
+        let mut reopened = GithubDb::new(dir.path(), None)?;
+        assert!(reopened.read("a").is_err());
+        reopened.set_encryption_key(&[1u8; 32])?;
+        assert_eq!(reopened.read("a")?.data, json!({ "n": 3 }));
+        assert_eq!(reopened.history("a")?.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_data_keys_are_written_with_their_documents() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        db.create("a", json!({ "n": 1 }))?;
+        let backend = db.storage.backend().clone();
+
+        // A document that can't be converted leaves the database as it was
+        backend.write("b.json", b"not a document")?;
+        assert!(db.enable_data_keys().is_err());
+        assert!(!db.data_keys_enabled()?);
+        assert!(data_key_paths(backend.as_ref())?.is_empty());
+        backend.delete("b.json")?;
+        db.enable_data_keys()?;
+
+        // Encoding a new document only returns the write of its key
+        let doc = db.read("a")?;
+        let ops = db.encode("c.json", &crate::Document { id: "c".to_string(), ..doc })?;
+        assert_eq!(ops.len(), 2);
+        assert!(!backend.exists(&data_key_path("c.json"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shred_and_purge() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        db.enable_data_keys()?;
+        db.create("a", json!({ "secret": 1 }))?;
+        db.update("a", json!({ "secret": 2 }))?;
+        db.create("b", json!({ "n": 1 }))?;
+        db.shred("b")?;
+        db.create("b", json!({ "n": 2 }))?;
+
+        // Shredding only destroys the current key
+        let head = db.git()?.resolve_commit_id("HEAD")?;
+        db.shred("a")?;
+        assert_eq!(db.git()?.resolve_commit_id("HEAD~1")?, head);
+        assert!(db.read("a").is_err());
+        assert!(!dir.path().join("keys/data/a.json.key").exists());
+        assert_eq!(db.read_at("a", "HEAD~4")?.data, json!({ "secret": 2 }));
+
+        // Every commit since the key was created is rewritten
+        assert_eq!(db.purge("a")?.rewritten, 6);
+        let git = db.git()?;
+        for rev in ["HEAD~1", "HEAD~3", "HEAD~4"] {
+            assert_eq!(git.read_file_at(rev, "keys/data/a.json.key")?, None);
+        }
+        // The ciphertext is still in history but can't be decrypted
+        assert!(git.read_file_at("HEAD~4", "a.json")?.is_some());
+        let err = db.read_at("a", "HEAD~4").unwrap_err().to_string();
+        assert!(err.contains("destroyed"), "{}", err);
+        assert!(db.history("a").is_err());
+        assert!(db.shred("a").is_err());
+
+        // A document re-created after being shredded keeps its new key
+        assert_eq!(db.read_at("b", "HEAD~3")?.data, json!({ "n": 1 }));
+        assert!(db.purge("b")?.rewritten > 0);
+        assert_eq!(db.read("b")?.data, json!({ "n": 2 }));
+        assert!(db.read_at("b", "HEAD~3").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_uses_data_keys_of_merged_branch() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        db.enable_data_keys()?;
+        db.create("a", json!({ "n": 1, "tag": "x" }))?;
+        let main = db.current_branch()?;
+
+        // The other branch replaces the document's key
+        db.create_branch("side", None)?;
+        db.checkout("side")?;
+        db.shred("a")?;
+        db.create("a", json!({ "n": 2, "tag": "x" }))?;
+        let side_key = std::fs::read(dir.path().join("keys/data/a.json.key"))?;
+        db.checkout(&main)?;
+        db.update("a", json!({ "n": 1, "tag": "y" }))?;
+
+        db.merge_branch("side", crate::MergePolicy::Fail)?;
+        assert_eq!(db.read("a")?.data, json!({ "n": 2, "tag": "y" }));
+        assert_eq!(std::fs::read(dir.path().join("keys/data/a.json.key"))?, side_key);
+        let reopened = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        assert_eq!(reopened.read("a")?.data, json!({ "n": 2, "tag": "y" }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_purge_reports_lost_signatures() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        let (cert, key) = db.generate_certificate("alice")?;
+        db.authenticate(&cert)?;
+        db.set_signer(crate::CommitSigner::new(&cert, &key)?);
+        db.enable_data_keys()?;
+        db.create("a", json!({}))?;
+        db.create("c", json!({}))?;
+        db.shred("a")?;
+        let roots = [cert];
+
+        // Re-signed by the current signer
+        let report = db.purge("a")?;
+        assert_eq!(report.rewritten, 3);
+        assert_eq!(report.lost_signatures.len(), 3);
+        assert_eq!(report.lost_signatures[0].message, "Create document a");
+        assert!(db.verify_history(&roots)?.iter().all(crate::CommitVerification::is_ok));
+
+        // Without a signer the rewritten commits end up unsigned
+        db.shred("c")?;
+        let mut unsigned = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        let report = unsigned.purge("c")?;
+        assert_eq!(report.lost_signatures.len(), 3);
+        let history = unsigned.verify_history(&roots)?;
+        assert_eq!(history.iter().filter(|v| !v.is_ok()).count(), 3);
+        Ok(())
+    }
+}
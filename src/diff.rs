@@ -0,0 +1,203 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldChange {
+    /// Path segments from the document root; array indices are rendered as numbers.
+    pub path: Vec<String>,
+    pub kind: ChangeKind,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+impl FieldChange {
+    /// The path in the dotted form accepted by filters, e.g. `address.city`.
+    pub fn dotted_path(&self) -> String {
+        dotted(&self.path)
+    }
+
+    /// The path as an RFC 6901 JSON Pointer, e.g. `/address/city`.
+    pub fn pointer(&self) -> String {
+        self.path.iter()
+            .map(|segment| format!("/{}", segment.replace('~', "~0").replace('/', "~1")))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DocumentDiff {
+    pub changes: Vec<FieldChange>,
+}
+
+impl DocumentDiff {
+    /// Computes the structural difference between two JSON values. `None`
+    /// stands for a document that does not exist on that side.
+    pub fn between(old: Option<&Value>, new: Option<&Value>) -> Self {
+        let mut changes = Vec::new();
+        let mut path = Vec::new();
+        match (old, new) {
+            (Some(old), Some(new)) => diff_values(&mut path, old, new, &mut changes),
+            (None, Some(new)) => changes.push(change(&path, ChangeKind::Added, None, Some(new))),
+            (Some(old), None) => changes.push(change(&path, ChangeKind::Removed, Some(old), None)),
+            (None, None) => {}
+        }
+        Self { changes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Renders the diff as an RFC 6902 JSON Patch that turns the old value
+    /// into the new one.
+    pub fn to_json_patch(&self) -> Value {
+        let ops = self.changes.iter()
+            .map(|change| match change.kind {
+                ChangeKind::Added => json!({ "op": "add", "path": change.pointer(), "value": change.new }),
+                ChangeKind::Removed => json!({ "op": "remove", "path": change.pointer() }),
+                ChangeKind::Changed => json!({ "op": "replace", "path": change.pointer(), "value": change.new }),
+            })
+            .collect();
+        Value::Array(ops)
+    }
+}
+
+impl fmt::Display for DocumentDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.changes {
+            let old = change.old.as_ref().map(Value::to_string).unwrap_or_default();
+            let new = change.new.as_ref().map(Value::to_string).unwrap_or_default();
+            match change.kind {
+                ChangeKind::Added => writeln!(f, "+ {}: {}", change.dotted_path(), new)?,
+                ChangeKind::Removed => writeln!(f, "- {}: {}", change.dotted_path(), old)?,
+                ChangeKind::Changed => writeln!(f, "~ {}: {} -> {}", change.dotted_path(), old, new)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn dotted(path: &[String]) -> String {
+    if path.is_empty() {
+        "(root)".to_string()
+    } else {
+        path.join(".")
+    }
+}
+
+fn change(path: &[String], kind: ChangeKind, old: Option<&Value>, new: Option<&Value>) -> FieldChange {
+    FieldChange {
+        path: path.to_vec(),
+        kind,
+        old: old.cloned(),
+        new: new.cloned(),
+    }
+}
+
+fn diff_values(path: &mut Vec<String>, old: &Value, new: &Value, changes: &mut Vec<FieldChange>) {
+    match (old, new) {
+        (Value::Object(old_map), Value::Object(new_map)) => {
+            for (key, old_value) in old_map {
+                path.push(key.clone());
+                match new_map.get(key) {
+                    Some(new_value) => diff_values(path, old_value, new_value, changes),
+                    None => changes.push(change(path, ChangeKind::Removed, Some(old_value), None)),
+                }
+                path.pop();
+            }
+            for (key, new_value) in new_map {
+                if !old_map.contains_key(key) {
+                    path.push(key.clone());
+                    changes.push(change(path, ChangeKind::Added, None, Some(new_value)));
+                    path.pop();
+                }
+            }
+        },
+        (Value::Array(old_items), Value::Array(new_items)) => {
+            let common = old_items.len().min(new_items.len());
+            for i in 0..common {
+                path.push(i.to_string());
+                diff_values(path, &old_items[i], &new_items[i], changes);
+                path.pop();
+            }
+            for (i, new_value) in new_items.iter().enumerate().skip(common) {
+                path.push(i.to_string());
+                changes.push(change(path, ChangeKind::Added, None, Some(new_value)));
+                path.pop();
+            }
+            // Remove trailing elements from the end so patch indices stay valid
+            for i in (common..old_items.len()).rev() {
+                path.push(i.to_string());
+                changes.push(change(path, ChangeKind::Removed, Some(&old_items[i]), None));
+                path.pop();
+            }
+        },
+        _ => {
+            if old != new {
+                changes.push(change(path, ChangeKind::Changed, Some(old), Some(new)));
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_structural_diff() {
+        let old = json!({
+            "name": "Alice",
+            "age": 30,
+            "tags": ["a", "b", "c"],
+            "address": { "city": "New York", "zip": "10001" }
+        });
+        let new = json!({
+            "name": "Alice",
+            "age": 31,
+            "tags": ["a"],
+            "address": { "city": "Boston", "a/b": true }
+        });
+
+        let diff = DocumentDiff::between(Some(&old), Some(&new));
+        assert_eq!(diff.to_string(), "\
+~ address.city: \"New York\" -> \"Boston\"
+- address.zip: \"10001\"
++ address.a/b: true
+~ age: 30 -> 31
+- tags.2: \"c\"
+- tags.1: \"b\"
+");
+        assert_eq!(diff.to_json_patch(), json!([
+            { "op": "replace", "path": "/address/city", "value": "Boston" },
+            { "op": "remove", "path": "/address/zip" },
+            { "op": "add", "path": "/address/a~1b", "value": true },
+            { "op": "replace", "path": "/age", "value": 31 },
+            { "op": "remove", "path": "/tags/2" },
+            { "op": "remove", "path": "/tags/1" },
+        ]));
+    }
+
+    #[test]
+    fn test_missing_sides() {
+        let value = json!({ "x": 1 });
+        assert!(DocumentDiff::between(Some(&value), Some(&value)).is_empty());
+        assert!(DocumentDiff::between(None, None).is_empty());
+
+        let created = DocumentDiff::between(None, Some(&value));
+        assert_eq!(created.to_json_patch(), json!([{ "op": "add", "path": "", "value": { "x": 1 } }]));
+
+        let deleted = DocumentDiff::between(Some(&value), None);
+        assert_eq!(deleted.changes[0].kind, ChangeKind::Removed);
+        assert_eq!(deleted.changes[0].dotted_path(), "(root)");
+    }
+}
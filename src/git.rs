@@ -1,9 +1,73 @@
 use anyhow::Result;
-use git2::{Repository, Signature};
+use crate::backend::{GitTreeBackend, StagedChanges};
+use crate::CommitSigner;
+use git2::{build::TreeUpdateBuilder, FileMode, Oid, Repository, Signature, Sort};
+use serde::{Deserialize, Serialize};
 use std::path::Path;
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommitInfo {
+    pub id: String,
+    pub timestamp: i64,
+    pub message: String,
+    pub author: String,
+}
+
+impl CommitInfo {
+    pub(crate) fn from_commit(commit: &git2::Commit) -> Self {
+        Self {
+            id: commit.id().to_string(),
+            timestamp: commit.time().seconds(),
+            message: commit.message().unwrap_or_default().trim_end().to_string(),
+            author: commit.author().name().unwrap_or_default().to_string(),
+        }
+    }
+}
+
+/// A person or service recorded as the author or committer of commits.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    pub name: String,
+    pub email: Option<String>,
+}
+
+impl Identity {
+    pub fn new(name: &str, email: Option<&str>) -> Self {
+        Self {
+            name: name.to_string(),
+            email: email.map(str::to_string),
+        }
+    }
+
+    /// git requires an email, so identities without one get a placeholder.
+    fn signature(&self) -> Result<Signature<'static>> {
+        let email = match &self.email {
+            Some(email) => email.clone(),
+            None => format!("{}@github-db", self.name),
+        };
+        Ok(Signature::now(&self.name, &email)?)
+    }
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Self::new("GithubDB", Some("githubdb@example.com"))
+    }
+}
+
 pub struct GitManager {
-    repo: Repository,
+    pub(crate) repo: Repository,
+    /// Who made the changes; falls back to the committer when unset.
+    author: Option<Identity>,
+    committer: Identity,
+    /// Signs every commit when set.
+    signer: Option<CommitSigner>,
+    /// Set for bare repositories and for handles working on a branch other
+    /// than HEAD's, where documents are written as blobs through a
+    /// [`GitTreeBackend`] instead of to a working tree.
+    pub(crate) staged: Option<StagedChanges>,
+    /// The branch reads and commits use instead of HEAD's.
+    pub(crate) branch: Option<String>,
 }
 
 impl GitManager {
@@ -12,28 +76,152 @@ impl GitManager {
             Ok(repo) => repo,
             Err(_) => Repository::init(path.as_ref())?,
         };
-        Ok(Self { repo })
+        Ok(Self { repo, author: None, committer: Identity::default(), signer: None, staged: None, branch: None })
     }
 
-    pub fn commit(&self, message: &str) -> Result<()> {
+    pub fn open_bare<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let repo = match Repository::open_bare(path.as_ref()) {
+            Ok(repo) => repo,
+            Err(_) => Repository::init_bare(path.as_ref())?,
+        };
+        Ok(Self { repo, author: None, committer: Identity::default(), signer: None, staged: Some(StagedChanges::default()), branch: None })
+    }
+
+    /// A storage backend reading and writing this bare repository's object
+    /// database. Blobs it writes are recorded by the next [`GitManager::commit`].
+    pub fn tree_backend(&self) -> Result<GitTreeBackend> {
+        let staged = self.staged.clone()
+            .ok_or_else(|| crate::DbError::Git("Tree backend requires a bare repository".to_string()))?;
+        Ok(GitTreeBackend::new(Repository::open_bare(self.repo.path())?, staged, self.branch.clone()))
+    }
+
+    /// Reads and commits against `name` from now on instead of the branch
+    /// HEAD points at, without moving HEAD or touching the working tree. The
+    /// branch must exist unless the repository has no commits yet. Returns
+    /// whether the handle now works through [`GitManager::tree_backend`]; it
+    /// doesn't if `name` is HEAD's branch anyway.
+    pub fn set_branch(&mut self, name: &str) -> Result<bool> {
+        let refname = format!("refs/heads/{}", name);
+        if !git2::Reference::is_valid_name(&refname) {
+            return Err(crate::DbError::Git(format!("Invalid branch name {}", name)).into());
+        }
+        if self.repo.find_reference(&refname).is_err() && !self.repo.is_empty()? {
+            return Err(crate::DbError::Git(format!("Branch {} does not exist", name)).into());
+        }
+        if self.staged.is_none() && self.current_branch().ok().as_deref() == Some(name) {
+            return Ok(false);
+        }
+        self.branch = Some(name.to_string());
+        self.staged.get_or_insert_with(StagedChanges::default);
+        Ok(true)
+    }
+
+    /// The reference commits move: the branch set with
+    /// [`GitManager::set_branch`], otherwise HEAD.
+    pub(crate) fn head_ref(&self) -> String {
+        match &self.branch {
+            Some(branch) => format!("refs/heads/{}", branch),
+            None => "HEAD".to_string(),
+        }
+    }
+
+    /// Like [`Repository::head`], for the branch this handle works on.
+    pub(crate) fn head(&self) -> Result<git2::Reference<'_>, git2::Error> {
+        match &self.branch {
+            Some(_) => self.repo.find_reference(&self.head_ref()),
+            None => self.repo.head(),
+        }
+    }
+
+    /// Records `author` as the author of subsequent commits.
+    pub fn set_author(&mut self, author: Option<Identity>) {
+        self.author = author;
+    }
+
+    pub fn set_committer(&mut self, committer: Identity) {
+        self.committer = committer;
+    }
+
+    pub fn set_signer(&mut self, signer: Option<CommitSigner>) {
+        self.signer = signer;
+    }
+
+    pub fn is_bare(&self) -> bool {
+        self.repo.is_bare()
+    }
+
+    fn head_tree(&self) -> Result<Option<git2::Tree<'_>>> {
+        match self.head() {
+            Ok(head) => Ok(Some(head.peel_to_tree()?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Builds the next tree from the working tree, re-scanning every file.
+    fn worktree_tree(&self) -> Result<Oid> {
         let mut index = self.repo.index()?;
         index.add_all(["."].iter(), git2::IndexAddOption::DEFAULT, None)?;
         index.write()?;
+        Ok(index.write_tree()?)
+    }
+
+    /// Builds the next tree by applying the staged blobs to HEAD's tree, which
+    /// only touches the trees along the changed paths.
+    fn staged_tree(&self, staged: &StagedChanges) -> Result<Oid> {
+        let changes = std::mem::take(&mut *staged.lock().unwrap());
+        let result = (|| -> Result<Oid> {
+            let base = match self.head_tree()? {
+                Some(tree) => tree,
+                None => self.repo.find_tree(self.repo.treebuilder(None)?.write()?)?,
+            };
+            let mut builder = TreeUpdateBuilder::new();
+            for (path, blob) in &changes {
+                match blob {
+                    Some(oid) => builder.upsert(path.as_str(), *oid, FileMode::Blob),
+                    None => builder.remove(path.as_str()),
+                };
+            }
+            Ok(builder.create_updated(&self.repo, &base)?)
+        })();
+
+        if result.is_err() {
+            // Keep the changes staged so a retry or rollback still sees them
+            let mut staged = staged.lock().unwrap();
+            for (path, blob) in changes {
+                staged.entry(path).or_insert(blob);
+            }
+        }
+        result
+    }
 
-        let tree_id = index.write_tree()?;
+    pub fn commit(&self, message: &str) -> Result<()> {
+        self.commit_with(message, self.signer.as_ref())
+    }
+
+    /// Like [`GitManager::commit`], signing with `fallback` if no signer is
+    /// set.
+    pub(crate) fn commit_signed_by(&self, message: &str, fallback: &CommitSigner) -> Result<()> {
+        self.commit_with(message, Some(self.signer.as_ref().unwrap_or(fallback)))
+    }
+
+    fn commit_with(&self, message: &str, signer: Option<&CommitSigner>) -> Result<()> {
+        let tree_id = match &self.staged {
+            Some(staged) => self.staged_tree(staged)?,
+            None => self.worktree_tree()?,
+        };
         let tree = self.repo.find_tree(tree_id)?;
 
-        let signature = Signature::now("GithubDB", "githubdb@example.com")?;
-        let parent_commit = match self.repo.head() {
+        let parent_commit = match self.head() {
             Ok(head) => Some(head.peel_to_commit()?),
             Err(_) => None,
         };
 
         let parents = parent_commit.as_ref().map(|c| vec![c]).unwrap_or_default();
-        self.repo.commit(
-            Some("HEAD"),
-            &signature,
-            &signature,
+        self.create_commit_with(
+            signer,
+            Some(&self.head_ref()),
+            &self.author()?,
+            &self.committer()?,
             message,
             &tree,
             parents.as_slice(),
@@ -41,4 +229,215 @@ impl GitManager {
 
         Ok(())
     }
+
+    /// Writes a commit, signed if a signer is set, and moves `update_ref` to
+    /// it. For `HEAD` the branch HEAD points at is moved.
+    pub(crate) fn create_commit(
+        &self,
+        update_ref: Option<&str>,
+        author: &Signature,
+        committer: &Signature,
+        message: &str,
+        tree: &git2::Tree,
+        parents: &[&git2::Commit],
+    ) -> Result<Oid> {
+        self.create_commit_with(self.signer.as_ref(), update_ref, author, committer, message, tree, parents)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create_commit_with(
+        &self,
+        signer: Option<&CommitSigner>,
+        update_ref: Option<&str>,
+        author: &Signature,
+        committer: &Signature,
+        message: &str,
+        tree: &git2::Tree,
+        parents: &[&git2::Commit],
+    ) -> Result<Oid> {
+        let oid = match signer {
+            Some(signer) => {
+                let buffer = self.repo.commit_create_buffer(author, committer, message, tree, parents)?;
+                let content = buffer.as_str()
+                    .ok_or_else(|| crate::DbError::Git("Commit is not valid UTF-8".to_string()))?;
+                let signature = signer.sign(content.as_bytes())?;
+                self.repo.commit_signed(content, &signature, Some("gpgsig"))?
+            },
+            None => self.repo.commit(None, author, committer, message, tree, parents)?,
+        };
+
+        let summary = message.lines().next().unwrap_or_default();
+        match update_ref {
+            Some("HEAD") => {
+                let head = self.repo.find_reference("HEAD")?;
+                match head.symbolic_target() {
+                    Some(target) => { self.repo.reference(target, oid, true, summary)?; },
+                    None => self.repo.set_head_detached(oid)?,
+                }
+            },
+            Some(refname) => { self.repo.reference(refname, oid, true, summary)?; },
+            None => {},
+        }
+        Ok(oid)
+    }
+
+    pub(crate) fn author(&self) -> Result<Signature<'static>> {
+        self.author.as_ref().unwrap_or(&self.committer).signature()
+    }
+
+    pub(crate) fn committer(&self) -> Result<Signature<'static>> {
+        self.committer.signature()
+    }
+
+    fn blob_id_at(commit: &git2::Commit, path: &str) -> Result<Option<Oid>> {
+        match commit.tree()?.get_path(Path::new(path)) {
+            Ok(entry) => Ok(Some(entry.id())),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub(crate) fn read_blob(&self, id: Oid) -> Result<Vec<u8>> {
+        Ok(self.repo.find_blob(id)?.content().to_vec())
+    }
+
+    /// Returns every commit reachable from HEAD that changed `path`, newest
+    /// first, together with the file contents at that commit (`None` when the
+    /// commit deleted the file).
+    pub fn file_history(&self, path: &str) -> Result<Vec<(CommitInfo, Option<Vec<u8>>)>> {
+        let head = match self.head() {
+            Ok(head) => head.peel_to_commit()?,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+        revwalk.push(head.id())?;
+
+        let mut history = Vec::new();
+        for oid in revwalk {
+            let commit = self.repo.find_commit(oid?)?;
+            let current = Self::blob_id_at(&commit, path)?;
+            let previous = match commit.parents().next() {
+                Some(parent) => Self::blob_id_at(&parent, path)?,
+                None => None,
+            };
+
+            if current != previous {
+                let data = current.map(|id| self.read_blob(id)).transpose()?;
+                history.push((CommitInfo::from_commit(&commit), data));
+            }
+        }
+
+        Ok(history)
+    }
+
+    /// Reads `path` as it was at `rev`, which may be anything `git rev-parse`
+    /// understands (commit id, abbreviated id, tag, branch, `HEAD~2`, ...).
+    pub fn read_file_at(&self, rev: &str, path: &str) -> Result<Option<Vec<u8>>> {
+        let commit = self.resolve(rev)?;
+        Self::blob_id_at(&commit, path)?
+            .map(|id| self.read_blob(id))
+            .transpose()
+    }
+
+    /// Names of the files directly inside `dir` as of `rev`.
+    pub fn list_files_at(&self, rev: &str, dir: &str) -> Result<Vec<String>> {
+        let tree = self.resolve(rev)?.tree()?;
+        let tree = if dir.is_empty() {
+            tree
+        } else {
+            match tree.get_path(Path::new(dir)) {
+                Ok(entry) => self.repo.find_tree(entry.id())?,
+                Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e.into()),
+            }
+        };
+        Ok(tree.iter()
+            .filter(|entry| entry.kind() == Some(git2::ObjectType::Blob))
+            .filter_map(|entry| entry.name().map(str::to_string))
+            .collect())
+    }
+
+    pub fn resolve_commit_id(&self, rev: &str) -> Result<String> {
+        Ok(self.resolve(rev)?.id().to_string())
+    }
+
+    /// Resolves `rev` to a commit. `HEAD` stands for the branch set with
+    /// [`GitManager::set_branch`], if any.
+    pub(crate) fn resolve(&self, rev: &str) -> Result<git2::Commit<'_>> {
+        let rewritten = match (&self.branch, rev.strip_prefix("HEAD")) {
+            (Some(_), Some(rest)) if rest.is_empty() || rest.starts_with(['~', '^', '@']) => {
+                format!("{}{}", self.head_ref(), rest)
+            },
+            _ => rev.to_string(),
+        };
+        let object = self.repo.revparse_single(&rewritten)
+            .map_err(|e| crate::DbError::Git(format!("Unknown revision {}: {}", rev, e.message())))?;
+        Ok(object.peel_to_commit()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StorageBackend;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_file_history() -> Result<()> {
+        let dir = tempdir()?;
+        let git = GitManager::new(dir.path())?;
+
+        fs::write(dir.path().join("a.json"), "1")?;
+        git.commit("first")?;
+        fs::write(dir.path().join("b.json"), "unrelated")?;
+        git.commit("second")?;
+        fs::write(dir.path().join("a.json"), "2")?;
+        git.commit("third")?;
+        fs::remove_file(dir.path().join("a.json"))?;
+        git.commit("fourth")?;
+
+        let history = git.file_history("a.json")?;
+        let messages: Vec<_> = history.iter().map(|(c, _)| c.message.as_str()).collect();
+        assert_eq!(messages, vec!["fourth", "third", "first"]);
+        assert_eq!(history[0].1, None);
+        assert_eq!(history[1].1.as_deref(), Some(&b"2"[..]));
+
+        assert_eq!(git.read_file_at("HEAD~1", "a.json")?.as_deref(), Some(&b"2"[..]));
+        assert_eq!(git.read_file_at("HEAD", "a.json")?, None);
+        assert!(git.read_file_at("no-such-rev", "a.json").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bare_commit_applies_staged_blobs() -> Result<()> {
+        let dir = tempdir()?;
+        let git = GitManager::open_bare(dir.path())?;
+        let backend = git.tree_backend()?;
+
+        backend.write("a.json", b"1")?;
+        backend.write("collections/users/b.json", b"2")?;
+        git.commit("first")?;
+        assert!(git.repo.is_bare());
+        assert!(!dir.path().join("a.json").exists());
+
+        backend.write("a.json", b"3")?;
+        assert_eq!(backend.read("a.json")?, b"3");
+        assert_eq!(git.read_file_at("HEAD", "a.json")?.as_deref(), Some(&b"1"[..]));
+
+        backend.delete("collections/users/b.json")?;
+        git.commit("second")?;
+        assert_eq!(git.read_file_at("HEAD", "a.json")?.as_deref(), Some(&b"3"[..]));
+        assert_eq!(git.read_file_at("HEAD", "collections/users/b.json")?, None);
+        assert!(backend.list_dirs("collections")?.is_empty());
+
+        let reopened = GitManager::open_bare(dir.path())?.tree_backend()?;
+        assert_eq!(reopened.read("a.json")?, b"3");
+        assert_eq!(reopened.list("")?, vec!["a.json"]);
+
+        Ok(())
+    }
 }
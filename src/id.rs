@@ -0,0 +1,140 @@
+use crate::DbError;
+use std::fmt;
+use std::str::FromStr;
+
+/// Longest escaped form accepted, leaving room for extensions and temporary
+/// file suffixes within the usual 255 byte file name limit.
+const MAX_ESCAPED_LEN: usize = 200;
+
+/// A validated document, collection or user identifier.
+///
+/// Any non-empty Unicode string without control characters is accepted. On
+/// disk the ID is stored in an escaped form: ASCII letters, digits, `-`, `_`
+/// and `.` are kept as-is, every other byte of the UTF-8 encoding is written
+/// as `%XX` (uppercase hex). A leading `.` is escaped as well, so an escaped
+/// ID can never be `.`, `..`, a hidden file or contain a path separator.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DocumentId(String);
+
+impl DocumentId {
+    pub fn new(id: &str) -> Result<Self, DbError> {
+        if id.is_empty() {
+            return Err(DbError::InvalidId("ID must not be empty".to_string()));
+        }
+        if id.chars().any(char::is_control) {
+            return Err(DbError::InvalidId(format!("{:?} contains control characters", id)));
+        }
+
+        let id = Self(id.to_string());
+        if id.escaped().len() > MAX_ESCAPED_LEN {
+            return Err(DbError::InvalidId(format!("{:?} is too long", id.0)));
+        }
+        Ok(id)
+    }
+
+    fn is_allowed(byte: u8, first: bool) -> bool {
+        byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' || (byte == b'.' && !first)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The escaped form used for file and directory names.
+    pub fn escaped(&self) -> String {
+        let mut escaped = String::with_capacity(self.0.len());
+        for (i, byte) in self.0.bytes().enumerate() {
+            if Self::is_allowed(byte, i == 0) {
+                escaped.push(byte as char);
+            } else {
+                escaped.push_str(&format!("%{:02X}", byte));
+            }
+        }
+        escaped
+    }
+
+    /// Reverses [`DocumentId::escaped`]. Only the canonical escaped form is
+    /// accepted, so that every ID has exactly one file name: lowercase hex
+    /// and escapes of characters that are kept as-is are rejected.
+    pub fn from_escaped(escaped: &str) -> Result<Self, DbError> {
+        let invalid = || DbError::InvalidId(format!("{:?} is not a valid escaped ID", escaped));
+
+        let bytes = escaped.as_bytes();
+        let mut raw = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'%' {
+                let hex = escaped.get(i + 1..i + 3).ok_or_else(invalid)?;
+                raw.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+                i += 3;
+            } else if Self::is_allowed(bytes[i], i == 0) {
+                raw.push(bytes[i]);
+                i += 1;
+            } else {
+                return Err(invalid());
+            }
+        }
+
+        let id = Self::new(&String::from_utf8(raw).map_err(|_| invalid())?)?;
+        if id.escaped() != escaped {
+            return Err(invalid());
+        }
+        Ok(id)
+    }
+}
+
+impl FromStr for DocumentId {
+    type Err = DbError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+impl fmt::Display for DocumentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escaping_round_trip() {
+        let cases = [
+            ("user-1_a.b", "user-1_a.b"),
+            ("../certs/admin", "%2E.%2Fcerts%2Fadmin"),
+            ("a/b", "a%2Fb"),
+            (".git", "%2Egit"),
+            ("100%", "100%25"),
+            ("héllo wörld", "h%C3%A9llo%20w%C3%B6rld"),
+        ];
+        for (raw, escaped) in cases {
+            let id = DocumentId::new(raw).unwrap();
+            assert_eq!(id.escaped(), escaped);
+            assert_eq!(DocumentId::from_escaped(escaped).unwrap(), id);
+        }
+    }
+
+    #[test]
+    fn test_rejected_ids() {
+        assert!(matches!(DocumentId::new(""), Err(DbError::InvalidId(_))));
+        assert!(DocumentId::new("a\nb").is_err());
+        assert!(DocumentId::new("\0").is_err());
+        assert!(DocumentId::new(&"x".repeat(MAX_ESCAPED_LEN + 1)).is_err());
+        assert!(DocumentId::new(&"/".repeat(MAX_ESCAPED_LEN / 3 + 1)).is_err());
+
+        assert!(DocumentId::from_escaped("a/b").is_err());
+        assert!(DocumentId::from_escaped("%2").is_err());
+        assert!(DocumentId::from_escaped("%FF").is_err());
+        assert!(DocumentId::from_escaped(".hidden").is_err());
+
+        // Only the canonical form names a file
+        assert!(DocumentId::from_escaped("%61").is_err());
+        assert!(DocumentId::from_escaped("a%2fb").is_err());
+        assert!(DocumentId::from_escaped("%2E.").is_ok());
+        assert!(DocumentId::from_escaped("%2E%2E").is_err());
+    }
+}
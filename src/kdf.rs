@@ -0,0 +1,128 @@
+use crate::{DbError, StorageBackend};
+use anyhow::Result;
+use base64::Engine;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Where the key derivation parameters of a database are stored. Not a
+/// `.json` file, so it's never mistaken for a document.
+pub(crate) const KDF_PATH: &str = "keys/kdf";
+/// Parameters for the passphrase a key rotation is switching to, kept until
+/// the rotation is committed so an interrupted one can be resumed.
+pub(crate) const PENDING_KDF_PATH: &str = "keys/kdf.pending";
+
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+/// An encryption key as supplied by the user.
+#[derive(Clone)]
+pub enum KeySource {
+    /// A 32-byte key used as is.
+    Raw(Vec<u8>),
+    /// A passphrase of any length; see [`crate::GithubDb::unlock`].
+    Passphrase(Vec<u8>),
+}
+
+/// How a passphrase is turned into the 32-byte encryption key. The salt is
+/// generated once per database, so the same passphrase yields different keys
+/// for different databases.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+pub(crate) enum KdfParams {
+    Scrypt {
+        /// Base64-encoded salt.
+        salt: String,
+        log_n: u8,
+        r: u32,
+        p: u32,
+    },
+}
+
+impl KdfParams {
+    /// Fresh parameters with a random salt.
+    pub fn generate() -> Self {
+        let salt: [u8; 16] = rand::thread_rng().gen();
+        KdfParams::Scrypt {
+            salt: base64::engine::general_purpose::STANDARD.encode(salt),
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+        }
+    }
+
+    pub fn derive(&self, passphrase: &[u8]) -> Result<[u8; 32]> {
+        if passphrase.is_empty() {
+            return Err(DbError::Encryption("Passphrase must not be empty".to_string()).into());
+        }
+        match self {
+            KdfParams::Scrypt { salt, log_n, r, p } => {
+                let salt = base64::engine::general_purpose::STANDARD.decode(salt)
+                    .map_err(|e| DbError::Encryption(format!("Invalid key derivation salt: {}", e)))?;
+                if *log_n == 0 || *log_n > 24 {
+                    return Err(DbError::Encryption(format!("Unsupported scrypt cost 2^{}", log_n)).into());
+                }
+                let n = 1u64 << log_n;
+                // scrypt needs 128 * N * r * p bytes; leave headroom for OpenSSL
+                let max_mem = 256 * n * u64::from(*r) * u64::from(*p);
+                let mut key = [0u8; 32];
+                openssl::pkcs5::scrypt(passphrase, &salt, n, u64::from(*r), u64::from(*p), max_mem, &mut key)
+                    .map_err(|e| DbError::Encryption(format!("Key derivation failed: {}", e)))?;
+                Ok(key)
+            },
+        }
+    }
+
+    /// Identifies these parameters in the envelope of data encrypted with a
+    /// key derived from them.
+    pub fn id(&self) -> [u8; 8] {
+        let serialized = serde_json::to_vec(self).unwrap_or_default();
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&openssl::sha::sha256(&serialized)[..8]);
+        id
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data)
+            .map_err(|e| DbError::Encryption(format!("Invalid key derivation parameters: {}", e)).into())
+    }
+
+    pub fn load(backend: &dyn StorageBackend, path: &str) -> Result<Option<Self>> {
+        if !backend.exists(path)? {
+            return Ok(None);
+        }
+        Self::parse(&backend.read(path)?).map(Some)
+    }
+
+    pub fn save(&self, backend: &dyn StorageBackend, path: &str) -> Result<()> {
+        backend.write(path, &self.to_bytes()?)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryBackend;
+
+    #[test]
+    fn test_derive_is_salted_and_stable() -> Result<()> {
+        let backend = MemoryBackend::new();
+        assert_eq!(KdfParams::load(&backend, KDF_PATH)?, None);
+
+        let params = KdfParams::generate();
+        params.save(&backend, KDF_PATH)?;
+        let loaded = KdfParams::load(&backend, KDF_PATH)?.unwrap();
+        assert_eq!(loaded, params);
+
+        let key = params.derive(b"correct horse battery staple")?;
+        assert_eq!(loaded.derive(b"correct horse battery staple")?, key);
+        assert_ne!(params.derive(b"correct horse battery stapler")?, key);
+        assert_ne!(KdfParams::generate().derive(b"correct horse battery staple")?, key);
+        assert!(params.derive(b"").is_err());
+        Ok(())
+    }
+}
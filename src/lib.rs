@@ -1,18 +1,54 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::path::Path;
+use std::sync::Arc;
 use thiserror::Error;
 use std::cmp::Ordering;
 
 mod crypto;
+mod diff;
 mod git;
+mod id;
+mod merge;
+mod remote;
+mod signing;
+mod snapshot;
 mod storage;
+mod transaction;
+mod backend;
+mod blame;
+mod branch;
 mod cert;
+mod changes;
+mod collection;
+mod compact;
+mod datakey;
+mod kdf;
+mod rename;
+mod rotate;
+mod watch;
 
-pub use crypto::Crypto;
-pub use git::GitManager;
+pub use backend::{BatchOp, FsBackend, GitTreeBackend, MemoryBackend, StorageBackend};
+pub use blame::FieldBlame;
+pub use branch::MergeOutcome;
+pub use changes::{ChangeEvent, EventKind};
+pub use collection::Collection;
+pub use compact::{CompactOptions, CompactReport, PurgeReport};
+pub use crypto::{Crypto, Envelope, KeyId};
+pub use diff::{ChangeKind, DocumentDiff, FieldChange};
+pub use git::{CommitInfo, GitManager, Identity};
+pub use id::DocumentId;
+pub use kdf::KeySource;
+pub use merge::{merge_documents, DocumentConflict, DocumentMerge, MergePolicy};
+pub use remote::{Credentials, PullOutcome, RemoteOptions};
+pub use rename::MisnamedFile;
+pub use signing::{CommitSigner, CommitVerification, SignatureStatus};
+pub use snapshot::Snapshot;
 pub use storage::Storage;
+pub use transaction::Transaction;
+pub use watch::{WatchOptions, Watcher};
 use cert::CertManager;
+use kdf::KdfParams;
 
 #[derive(Error, Debug)]
 pub enum DbError {
@@ -28,14 +64,60 @@ pub enum DbError {
     Certificate(String),
     #[error("Filter error: {0}")]
     Filter(String),
+    #[error("Invalid ID: {0}")]
+    InvalidId(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Push of branch {0} rejected: the remote has commits that are not present locally, pull first")]
+    NonFastForward(String),
+    #[error("Merge conflict in {}", merge::format_conflicts(.0))]
+    MergeConflict(Vec<DocumentConflict>),
+    #[error("Wrong encryption key: the data was encrypted with key {expected}, but key {actual} was supplied")]
+    WrongKey {
+        expected: String,
+        actual: String,
+    },
+    #[error("Tampering detected: {0}")]
+    Tampered(String),
+    #[error("Conflict on document {id}: expected version {expected}, found {actual}")]
+    Conflict {
+        id: String,
+        expected: u64,
+        actual: u64,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Document {
     pub id: String,
     pub data: serde_json::Value,
     pub created_at: u64,
     pub updated_at: u64,
+    /// Incremented on every write, starting at 1. Documents written before
+    /// versioning was introduced read as version 0.
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl Document {
+    /// Fails with [`DbError::Conflict`] unless the document is at `expected`.
+    pub(crate) fn check_version(&self, expected: Option<u64>) -> Result<(), DbError> {
+        match expected {
+            Some(expected) if expected != self.version => Err(DbError::Conflict {
+                id: self.id.clone(),
+                expected,
+                actual: self.version,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Revision {
+    pub commit: CommitInfo,
+    /// `None` when this revision deleted the document.
+    pub document: Option<Document>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -161,139 +243,660 @@ fn get_nested_value<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a
 
 pub struct GithubDb {
     storage: Storage,
-    git: GitManager,
+    git: Option<GitManager>,
     crypto: Option<Crypto>,
     cert_manager: CertManager,
+    /// See [`GithubDb::allow_legacy_encryption`].
+    legacy_encryption: bool,
 }
 
 impl GithubDb {
     pub fn new<P: AsRef<Path>>(path: P, encryption_key: Option<&[u8]>) -> Result<Self> {
-        let storage = Storage::new(path.as_ref())?;
+        let backend = Arc::new(FsBackend::new(path.as_ref())?);
         let git = GitManager::new(path.as_ref())?;
+        Self::open(backend, Some(git), encryption_key)
+    }
+
+    /// Opens a database on a bare git repository. Documents live only in the
+    /// git object database: reads come from HEAD's tree and each commit writes
+    /// just the blobs and trees along the changed paths.
+    pub fn open_bare<P: AsRef<Path>>(path: P, encryption_key: Option<&[u8]>) -> Result<Self> {
+        let git = GitManager::open_bare(path.as_ref())?;
+        let backend = Arc::new(git.tree_backend()?);
+        Self::open(backend, Some(git), encryption_key)
+    }
+
+    /// Opens a database on an arbitrary storage backend. Such a database has
+    /// no git repository: writes are not versioned and history, diff and
+    /// restore return an error.
+    pub fn with_backend(backend: Arc<dyn StorageBackend>, encryption_key: Option<&[u8]>) -> Result<Self> {
+        Self::open(backend, None, encryption_key)
+    }
+
+    /// A database kept entirely in memory, mainly for tests.
+    pub fn in_memory(encryption_key: Option<&[u8]>) -> Result<Self> {
+        Self::with_backend(Arc::new(MemoryBackend::new()), encryption_key)
+    }
+
+    fn open(backend: Arc<dyn StorageBackend>, git: Option<GitManager>, encryption_key: Option<&[u8]>) -> Result<Self> {
         let crypto = if let Some(key) = encryption_key {
             Some(Crypto::new(key)?)
         } else {
             None
         };
-        let cert_manager = CertManager::new(path.as_ref(), encryption_key)?;
+        let cert_manager = CertManager::new(backend.clone(), encryption_key)?;
 
         Ok(Self {
-            storage,
+            storage: Storage::with_backend(backend),
             git,
             crypto,
             cert_manager,
+            legacy_encryption: false,
         })
     }
 
+    /// Switches to encrypting with a raw 32-byte key.
+    pub fn set_encryption_key(&mut self, key: &[u8]) -> Result<()> {
+        self.set_crypto(Crypto::new(key)?);
+        Ok(())
+    }
+
+    /// Keeps `key` around for decrypting files that are still encrypted
+    /// with it, e.g. older revisions after a key rotation. New data is still
+    /// encrypted with the current key.
+    pub fn add_decryption_key(&mut self, key: &[u8]) -> Result<()> {
+        let mut crypto = self.crypto.clone()
+            .ok_or_else(|| DbError::Encryption("Set an encryption key first".to_string()))?;
+        crypto.add_key(key)?;
+        self.set_crypto(crypto);
+        Ok(())
+    }
+
+    /// Also reads files in the encryption formats that aren't bound to their
+    /// path, see [`Crypto::allow_legacy`]. Needed to upgrade a database
+    /// written before files were bound to their path, by rotating to a new
+    /// key, and to read revisions from before the upgrade.
+    pub fn allow_legacy_encryption(&mut self) {
+        self.legacy_encryption = true;
+        if let Some(crypto) = self.crypto.clone() {
+            self.set_crypto(crypto);
+        }
+    }
+
+    pub(crate) fn set_crypto(&mut self, mut crypto: Crypto) {
+        if self.legacy_encryption {
+            crypto.allow_legacy();
+        }
+        self.cert_manager.set_crypto(crypto.clone());
+        self.crypto = Some(crypto);
+    }
+
+    /// Derives the encryption key from a passphrase with scrypt, using the
+    /// salt and parameters stored in the database. An empty database gets
+    /// fresh parameters, written to storage and committed with the first
+    /// write. A database that was encrypted before it had parameters keeps
+    /// treating a 32-byte passphrase as the raw key. Fails if the key doesn't
+    /// decrypt the stored data, before anything is written.
+    pub fn unlock(&mut self, passphrase: &[u8]) -> Result<()> {
+        let backend = self.storage.backend().clone();
+        let mut crypto = match KdfParams::load(backend.as_ref(), kdf::KDF_PATH)? {
+            Some(params) => Crypto::with_kdf_id(&params.derive(passphrase)?, params.id())?,
+            None if !self.has_data()? => {
+                let params = KdfParams::generate();
+                let crypto = Crypto::with_kdf_id(&params.derive(passphrase)?, params.id())?;
+                params.save(backend.as_ref(), kdf::KDF_PATH)?;
+                crypto
+            },
+            None if passphrase.len() == 32 => Crypto::new(passphrase)?,
+            None => return Err(DbError::Encryption(
+                "Database has no key derivation parameters; it is encrypted with a raw 32-byte key".to_string()
+            ).into()),
+        };
+        if self.legacy_encryption {
+            crypto.allow_legacy();
+        }
+        self.check_key(&crypto)?;
+        self.set_crypto(crypto);
+        Ok(())
+    }
+
+    /// Decrypts one stored file encrypted with the encryption key, so that a
+    /// wrong key fails up front rather than on first use.
+    fn check_key(&self, crypto: &Crypto) -> Result<()> {
+        let backend = self.storage.backend();
+        let mut paths = datakey::data_key_paths(backend.as_ref())?;
+        paths.extend(self.document_paths()?);
+        paths.extend(backend.list("certs")?.into_iter().map(|name| format!("certs/{}", name)));
+        for path in paths {
+            let data = backend.read(&path)?;
+            let encrypted = match Envelope::parse(&data)? {
+                Some(envelope) => !envelope.uses_data_key(),
+                // Legacy ciphertext without a header, as opposed to plaintext
+                None => path.ends_with(".json") && serde_json::from_slice::<serde_json::Value>(&data).is_err(),
+            };
+            if encrypted {
+                crypto.decrypt_for(&data, path.as_bytes())?;
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the encryption key from either kind of [`KeySource`].
+    pub fn set_key(&mut self, key: &KeySource) -> Result<()> {
+        match key {
+            KeySource::Raw(key) => self.set_encryption_key(key),
+            KeySource::Passphrase(passphrase) => self.unlock(passphrase),
+        }
+    }
+
+    fn has_data(&self) -> Result<bool> {
+        Ok(!self.list()?.is_empty() || !self.list_collections()?.is_empty() || !self.list_certificates()?.is_empty())
+    }
+
+    pub(crate) fn git(&self) -> Result<&GitManager> {
+        self.git.as_ref()
+            .ok_or_else(|| DbError::Git("Database has no git repository".to_string()).into())
+    }
+
+    /// Records the current state of the storage as a commit. A no-op for
+    /// databases without a git repository.
+    pub(crate) fn commit(&self, message: &str) -> Result<()> {
+        match &self.git {
+            Some(git) => git.commit(message),
+            None => Ok(()),
+        }
+    }
+
+    /// Generates and stores a certificate. The commit is signed by the
+    /// current signer, or else by the new certificate itself, which
+    /// [`GithubDb::verify_history`] only accepts if it is trusted up front.
     pub fn generate_certificate(&self, username: &str) -> Result<(Vec<u8>, Vec<u8>)> {
-        self.cert_manager.generate_cert(username)
+        let (cert, key) = self.cert_manager.generate_cert(username)?;
+        self.commit_certificate(username, &cert, &key)?;
+        Ok((cert, key))
+    }
+
+    /// Like [`GithubDb::generate_certificate`], additionally recording
+    /// `email` in the certificate so commits carry it as the author email.
+    pub fn generate_certificate_with_email(&self, username: &str, email: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+        let (cert, key) = self.cert_manager.generate_cert_with_email(username, Some(email))?;
+        self.commit_certificate(username, &cert, &key)?;
+        Ok((cert, key))
+    }
+
+    fn commit_certificate(&self, username: &str, cert: &[u8], key: &[u8]) -> Result<()> {
+        match &self.git {
+            Some(git) => git.commit_signed_by(&format!("Add certificate for {}", username), &CommitSigner::new(cert, key)?),
+            None => Ok(()),
+        }
     }
 
     pub fn verify_certificate(&self, cert_data: &[u8]) -> Result<bool> {
         let cert = openssl::x509::X509::from_pem(cert_data)
             .map_err(|e| DbError::Certificate(format!("Invalid certificate: {}", e)))?;
-        
-        let subject_name = cert.subject_name();
-        let cn = subject_name.entries_by_nid(openssl::nid::Nid::COMMONNAME)
-            .next()
-            .ok_or_else(|| DbError::Certificate("No username found in certificate".to_string()))?;
-        
-        let username = cn.data().as_utf8()
-            .map_err(|e| DbError::Certificate(format!("Invalid username encoding: {}", e)))?;
+        let identity = cert::identity(&cert)?;
+
+        self.cert_manager.verify_cert(&identity.name, cert_data)
+    }
+
+    /// Verifies the certificate and records its holder as the author of
+    /// every following commit.
+    pub fn authenticate(&mut self, cert_data: &[u8]) -> Result<Identity> {
+        if !self.verify_certificate(cert_data)? {
+            return Err(DbError::Certificate("Invalid or revoked certificate".to_string()).into());
+        }
+        let cert = openssl::x509::X509::from_pem(cert_data)?;
+        let identity = cert::identity(&cert)?;
+        if let Some(git) = &mut self.git {
+            git.set_author(Some(identity.clone()));
+        }
+        Ok(identity)
+    }
+
+    /// Signs every following commit with `signer`.
+    pub fn set_signer(&mut self, signer: CommitSigner) {
+        if let Some(git) = &mut self.git {
+            git.set_signer(Some(signer));
+        }
+    }
 
-        self.cert_manager.verify_cert(username.to_string().as_str(), cert_data)
+    /// Checks the signature of every commit reachable from HEAD, newest
+    /// first. Only the `trusted` certificates (PEM) are trusted up front. A
+    /// certificate stored in the database becomes trusted once a validly
+    /// signed commit adds it, and stops being trusted once a validly signed
+    /// commit revokes it; certificate changes in any other commit are
+    /// ignored. Every commit must be validly signed, by a certificate that
+    /// was valid at the commit's time.
+    ///
+    /// Stored certificates are decrypted with the keys of this handle, so
+    /// after a key rotation the old key must be added with
+    /// [`GithubDb::add_decryption_key`] to read those from before it.
+    pub fn verify_history(&self, trusted: &[Vec<u8>]) -> Result<Vec<CommitVerification>> {
+        use openssl::x509::X509;
+
+        let roots = trusted.iter()
+            .map(|pem| {
+                let cert = X509::from_pem(pem)
+                    .map_err(|e| DbError::Certificate(format!("Invalid certificate: {}", e)))?;
+                Ok((cert::identity(&cert)?.name, cert))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if roots.is_empty() {
+            return Err(DbError::Certificate("Verifying history needs at least one trusted certificate".to_string()).into());
+        }
+
+        // The stored certificates trusted after each commit
+        let mut trust: std::collections::HashMap<String, Vec<(String, X509)>> = Default::default();
+        let mut verifications = Vec::new();
+        for commit in self.git()?.signed_history()? {
+            let mut stored: Vec<(String, X509)> = commit.parent.as_ref()
+                .and_then(|parent| trust.get(parent))
+                .cloned()
+                .unwrap_or_default();
+            let (names, certs): (Vec<_>, Vec<_>) = roots.iter().chain(&stored).cloned().unzip();
+            let status = match &commit.signature {
+                None => SignatureStatus::Unsigned,
+                Some((signature, data)) => match signing::find_signer(signature, data, &certs)? {
+                    Some(i) => match signing::validity_problem(&certs[i], commit.info.timestamp)? {
+                        None => SignatureStatus::Valid { signer: names[i].clone() },
+                        Some(reason) => SignatureStatus::Invalid { reason: reason.to_string() },
+                    },
+                    None => SignatureStatus::Invalid {
+                        reason: "not signed by a trusted certificate".to_string(),
+                    },
+                },
+            };
+
+            if let SignatureStatus::Valid { .. } = status {
+                for (name, pem) in &commit.cert_changes {
+                    stored.retain(|(stored_name, _)| stored_name != name);
+                    let (path, _) = self.cert_manager.cert_paths(name)?;
+                    let pem = pem.as_ref()
+                        .map(|pem| self.cert_manager.decrypt_data(&path, pem))
+                        .transpose()
+                        .map_err(|e| DbError::Certificate(format!(
+                            "Certificate for {} changed in commit {} can't be decrypted, add the key it was encrypted with: {}",
+                            name, &commit.info.id[..7], e
+                        )))?;
+                    let cert = pem.and_then(|pem| X509::from_pem(&pem).ok());
+                    if let Some(cert) = cert {
+                        stored.push((name.clone(), cert));
+                    }
+                }
+            }
+            trust.insert(commit.info.id.clone(), stored);
+            verifications.push(CommitVerification { commit: commit.info, status });
+        }
+        verifications.reverse();
+        Ok(verifications)
+    }
+
+    /// Sets the identity recorded as committer, by default `GithubDB`.
+    pub fn set_committer(&mut self, committer: Identity) {
+        if let Some(git) = &mut self.git {
+            git.set_committer(committer);
+        }
     }
 
     pub fn revoke_certificate(&self, username: &str) -> Result<()> {
-        self.cert_manager.revoke_cert(username)
+        self.cert_manager.revoke_cert(username)?;
+        self.commit(&format!("Revoke certificate for {}", username))
     }
 
     pub fn list_certificates(&self) -> Result<Vec<String>> {
         self.cert_manager.list_certs()
     }
 
-    pub fn create(&mut self, id: &str, data: serde_json::Value) -> Result<Document> {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_secs();
-
-        let doc = Document {
-            id: id.to_string(),
-            data,
-            created_at: now,
-            updated_at: now,
-        };
-
-        let json = serde_json::to_string(&doc)?;
-        let encrypted = if let Some(crypto) = &self.crypto {
-            crypto.encrypt(json.as_bytes())?
-        } else {
-            json.into_bytes()
+    /// Serializes and, with a key, encrypts a document stored at `path`. The
+    /// ciphertext is bound to the path, so it can't be moved elsewhere.
+    /// Returns the writes storing it: the document and, if it gets a new
+    /// data key, the key, so that both go into the same batch.
+    pub(crate) fn encode(&self, path: &str, doc: &Document) -> Result<Vec<BatchOp>> {
+        let (data_key, mut ops) = match &self.crypto {
+            Some(crypto) if self.data_keys_enabled()? => {
+                let (data_key, new_key) = self.data_key_for_write(crypto, path)?;
+                (Some(data_key), new_key.into_iter().collect())
+            },
+            _ => (None, Vec::new()),
         };
+        ops.push((path.to_string(), Some(self.encode_with(path, doc, data_key.as_deref())?)));
+        Ok(ops)
+    }
 
-        self.storage.write(&doc.id, &encrypted)?;
-        self.git.commit(&format!("Create document {}", doc.id))?;
+    /// Like [`GithubDb::encode`], with the document's data key if it has one.
+    fn encode_with(&self, path: &str, doc: &Document, data_key: Option<&[u8]>) -> Result<Vec<u8>> {
+        let json = serde_json::to_string(doc)?;
+        match (&self.crypto, data_key) {
+            (Some(_), Some(data_key)) => Crypto::encrypt_with_data_key(data_key, json.as_bytes(), path.as_bytes()),
+            (Some(crypto), None) => crypto.encrypt_for(json.as_bytes(), path.as_bytes()),
+            (None, _) => Ok(json.into_bytes()),
+        }
+    }
 
-        Ok(doc)
+    /// The inverse of [`GithubDb::encode`]. Also checks that the document is
+    /// the one its path says it is.
+    pub(crate) fn decode(&self, path: &str, data: Vec<u8>) -> Result<Document> {
+        self.decode_at(path, data, None)
     }
 
-    pub fn read(&self, id: &str) -> Result<Document> {
-        let data = self.storage.read(id)?;
+    /// Like [`GithubDb::decode`] for a document read at `rev`, where its data
+    /// key is looked for as well.
+    pub(crate) fn decode_at(&self, path: &str, data: Vec<u8>, rev: Option<&str>) -> Result<Document> {
         let json = if let Some(crypto) = &self.crypto {
-            String::from_utf8(crypto.decrypt(&data)?)?
+            let plaintext = match Envelope::parse(&data)? {
+                Some(envelope) if envelope.uses_data_key() => {
+                    let data_key = self.data_key(crypto, path, envelope.key_id, rev)?;
+                    Crypto::decrypt_with_data_key(&data_key, &data, path.as_bytes())?
+                },
+                _ => crypto.decrypt_for(&data, path.as_bytes())?,
+            };
+            String::from_utf8(plaintext)?
+        } else if Crypto::is_encrypted(&data) {
+            return Err(DbError::Encryption("Document is encrypted, an encryption key is required".to_string()).into());
         } else {
             String::from_utf8(data)?
         };
 
-        Ok(serde_json::from_str(&json)?)
+        let doc: Document = serde_json::from_str(&json)?;
+        if let Some((_, id)) = Storage::parse_path(path) {
+            if id != doc.id {
+                return Err(DbError::Tampered(format!("{} holds document {}", path, doc.id)).into());
+            }
+        }
+        Ok(doc)
+    }
+
+    /// The collection stored directly in the database root, used by the
+    /// document methods on `GithubDb` itself.
+    pub fn default_collection(&self) -> Collection<'_> {
+        Collection::new(self, self.storage.clone(), None)
     }
 
-    pub fn update(&mut self, id: &str, data: serde_json::Value) -> Result<Document> {
-        let mut doc = self.read(id)?;
-        doc.data = data;
-        doc.updated_at = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_secs();
-
-        let json = serde_json::to_string(&doc)?;
-        let encrypted = if let Some(crypto) = &self.crypto {
-            crypto.encrypt(json.as_bytes())?
-        } else {
-            json.into_bytes()
+    pub fn collection(&self, name: &str) -> Result<Collection<'_>> {
+        Ok(Collection::new(self, self.storage.collection(name)?, Some(name)))
+    }
+
+    pub fn list_collections(&self) -> Result<Vec<String>> {
+        self.storage.list_collections()
+    }
+
+    pub fn drop_collection(&mut self, name: &str) -> Result<()> {
+        self.storage.drop_collection(name)?;
+        self.commit(&format!("Drop collection {}", name))?;
+        Ok(())
+    }
+
+    pub fn pull(&mut self, options: &RemoteOptions) -> Result<PullOutcome> {
+        self.git()?.pull(options, &mut |path, commits, base, ours, theirs| {
+            self.merge_blobs(options.merge_policy, path, commits, base, ours, theirs, &mut Vec::new())
+        })
+    }
+
+    pub fn push(&mut self, options: &RemoteOptions) -> Result<()> {
+        self.git()?.push(options)
+    }
+
+    /// Pulls remote changes, rebasing local commits on top of them, and
+    /// pushes the result.
+    pub fn sync(&mut self, options: &RemoteOptions) -> Result<PullOutcome> {
+        self.git()?.sync(options, &mut |path, commits, base, ours, theirs| {
+            self.merge_blobs(options.merge_policy, path, commits, base, ours, theirs, &mut Vec::new())
+        })
+    }
+
+    pub fn current_branch(&self) -> Result<String> {
+        self.git()?.current_branch()
+    }
+
+    /// Points HEAD at `name` and checks it out into the working tree, so that
+    /// this and every later handle read and commit to that branch.
+    pub fn checkout(&mut self, name: &str) -> Result<()> {
+        self.git()?.checkout(name)
+    }
+
+    /// Makes this handle read from and commit to `name` without moving HEAD
+    /// or touching the working tree, which other handles keep using. Call it
+    /// before writing anything.
+    pub fn set_branch(&mut self, name: &str) -> Result<()> {
+        let git = self.git.as_mut()
+            .ok_or_else(|| DbError::Git("Database has no git repository".to_string()))?;
+        if git.set_branch(name)? {
+            let backend: Arc<dyn StorageBackend> = Arc::new(git.tree_backend()?);
+            self.storage = Storage::with_backend(backend.clone());
+            self.cert_manager = CertManager::new(backend, None)?;
+            if let Some(crypto) = &self.crypto {
+                self.cert_manager.set_crypto(crypto.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a branch at `from` (defaults to the current commit).
+    pub fn create_branch(&self, name: &str, from: Option<&str>) -> Result<()> {
+        self.git()?.create_branch(name, from)
+    }
+
+    pub fn list_branches(&self) -> Result<Vec<String>> {
+        self.git()?.list_branches()
+    }
+
+    /// Freezes the current state under `name`. Pass the name to `read_at`,
+    /// `find_at` or `diff` to read from the snapshot later.
+    pub fn create_snapshot(&self, name: &str, message: Option<&str>) -> Result<Snapshot> {
+        self.git()?.create_snapshot(name, message)
+    }
+
+    pub fn list_snapshots(&self) -> Result<Vec<Snapshot>> {
+        self.git()?.list_snapshots()
+    }
+
+    pub fn delete_snapshot(&self, name: &str) -> Result<()> {
+        self.git()?.delete_snapshot(name)
+    }
+
+    /// Every document change committed after `since` (from the beginning if
+    /// `None`), oldest first. With `include_values` each event carries the
+    /// document before and after the change.
+    pub fn changes_since(&self, since: Option<&str>, include_values: bool) -> Result<Vec<ChangeEvent>> {
+        let git = self.git()?;
+        let head = match git.head_id()? {
+            Some(head) => head,
+            None => return Ok(Vec::new()),
+        };
+        let since = since.map(|rev| git.resolve(rev).map(|commit| commit.id())).transpose()?;
+        self.changes_between(since, head, include_values)
+    }
+
+    pub(crate) fn changes_between(&self, since: Option<git2::Oid>, until: git2::Oid, include_values: bool) -> Result<Vec<ChangeEvent>> {
+        let git = self.git()?;
+        let mut events = Vec::new();
+        for (commit, changes) in git.tree_changes(since, until)? {
+            for change in changes {
+                let (collection, id) = match Storage::parse_path(&change.path) {
+                    Some(parsed) => parsed,
+                    None => continue,
+                };
+                let kind = match (change.old, change.new) {
+                    (None, _) => EventKind::Created,
+                    (_, None) => EventKind::Deleted,
+                    _ => EventKind::Updated,
+                };
+                let load = |blob: Option<git2::Oid>| -> Result<Option<Document>> {
+                    match blob {
+                        Some(blob) if include_values => {
+                            Ok(Some(self.decode_at(&change.path, git.read_blob(blob)?, Some(&commit.id))?))
+                        },
+                        _ => Ok(None),
+                    }
+                };
+                events.push(ChangeEvent {
+                    kind,
+                    collection,
+                    id,
+                    commit: commit.id.clone(),
+                    author: commit.author.clone(),
+                    timestamp: commit.timestamp,
+                    before: load(change.old)?,
+                    after: load(change.new)?,
+                });
+            }
+        }
+        Ok(events)
+    }
+
+    /// Starts watching the current branch for changes committed after
+    /// `since`, or after the current tip if `None`.
+    pub fn watch(&self, since: Option<&str>, options: WatchOptions) -> Result<Watcher> {
+        Watcher::new(self, since, options)
+    }
+
+    /// Drops old revisions from the current branch; see [`GitManager::compact`].
+    pub fn compact(&mut self, options: &CompactOptions) -> Result<CompactReport> {
+        self.git()?.compact(options)
+    }
+
+    pub fn delete_branch(&self, name: &str) -> Result<()> {
+        self.git()?.delete_branch(name)
+    }
+
+    /// Merges the documents of `source` into the current branch. Documents
+    /// changed on both branches are merged field by field; with
+    /// [`MergePolicy::Fail`] any true conflict aborts the merge with
+    /// [`DbError::MergeConflict`] listing every conflicting document.
+    pub fn merge_branch(&mut self, source: &str, policy: MergePolicy) -> Result<MergeOutcome> {
+        let mut settled = Vec::new();
+        let outcome = self.git()?.merge_branch(source, &mut |path, commits, base, ours, theirs| {
+            self.merge_blobs(policy, path, commits, base, ours, theirs, &mut settled)
+        })?;
+        Ok(match outcome {
+            MergeOutcome::Merged(_) => MergeOutcome::Merged(settled),
+            outcome => outcome,
+        })
+    }
+
+    /// Decrypts and merges three versions of a stored document, each with the
+    /// data key of the commit it comes from. Conflicts settled by the policy
+    /// are appended to `settled`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn merge_blobs(
+        &self,
+        policy: MergePolicy,
+        path: &str,
+        commits: &merge::MergeCommits,
+        base: Option<&[u8]>,
+        ours: Option<&[u8]>,
+        theirs: Option<&[u8]>,
+        settled: &mut Vec<DocumentConflict>,
+    ) -> Result<Option<Vec<u8>>> {
+        let conflict = |fields: Vec<String>| DbError::MergeConflict(vec![DocumentConflict {
+            path: path.to_string(),
+            fields,
+        }]);
+        // Certificates and other non-document files can't be merged
+        if !path.ends_with(".json") {
+            return Err(conflict(Vec::new()).into());
+        }
+
+        let decode = |data: Option<&[u8]>, commit: Option<git2::Oid>| {
+            data.map(|data| self.decode_at(path, data.to_vec(), commit.map(|oid| oid.to_string()).as_deref())).transpose()
         };
+        let merge = merge_documents(
+            decode(base, commits.base)?.as_ref(),
+            decode(ours, Some(commits.ours))?.as_ref(),
+            decode(theirs, Some(commits.theirs))?.as_ref(),
+            policy,
+        );
+        if !merge.conflicts.is_empty() {
+            if policy == MergePolicy::Fail {
+                return Err(conflict(merge.conflicts).into());
+            }
+            settled.push(DocumentConflict { path: path.to_string(), fields: merge.conflicts });
+        }
+        let data_key = match &self.crypto {
+            Some(crypto) => self.merged_data_key(crypto, path, commits)?,
+            None => None,
+        };
+        merge.document.map(|doc| self.encode_with(path, &doc, data_key.as_deref())).transpose()
+    }
 
-        self.storage.write(&doc.id, &encrypted)?;
-        self.git.commit(&format!("Update document {}", doc.id))?;
+    pub fn create(&mut self, id: &str, data: serde_json::Value) -> Result<Document> {
+        self.default_collection().create(id, data)
+    }
 
-        Ok(doc)
+    pub fn read(&self, id: &str) -> Result<Document> {
+        self.default_collection().read(id)
+    }
+
+    pub fn read_at(&self, id: &str, rev: &str) -> Result<Document> {
+        self.default_collection().read_at(id, rev)
+    }
+
+    /// Compares the document's data between two revisions. `from` defaults to
+    /// HEAD and `to` defaults to the working copy.
+    pub fn diff(&self, id: &str, from: Option<&str>, to: Option<&str>) -> Result<DocumentDiff> {
+        self.default_collection().diff(id, from, to)
+    }
+
+    pub fn blame(&self, id: &str) -> Result<Vec<FieldBlame>> {
+        self.default_collection().blame(id)
+    }
+
+    pub fn history(&self, id: &str) -> Result<Vec<Revision>> {
+        self.default_collection().history(id)
+    }
+
+    pub fn update(&mut self, id: &str, data: serde_json::Value) -> Result<Document> {
+        self.default_collection().update(id, data)
+    }
+
+    /// Like [`GithubDb::update`], but fails with [`DbError::Conflict`] if the
+    /// document is no longer at `expected_version`.
+    pub fn update_if(&mut self, id: &str, expected_version: u64, data: serde_json::Value) -> Result<Document> {
+        self.default_collection().update_if(id, expected_version, data)
+    }
+
+    /// Writes the document as it was at `rev` back as a new commit. Works for
+    /// documents that have since been deleted.
+    pub fn restore(&mut self, id: &str, rev: &str) -> Result<Document> {
+        self.default_collection().restore(id, rev)
     }
 
     pub fn delete(&mut self, id: &str) -> Result<()> {
-        self.storage.delete(id)?;
-        self.git.commit(&format!("Delete document {}", id))?;
-        Ok(())
+        self.default_collection().delete(id)
+    }
+
+    /// Deletes the document and destroys its data key; see
+    /// [`Collection::shred`].
+    pub fn shred(&mut self, id: &str) -> Result<()> {
+        self.default_collection().shred(id)
+    }
+
+    /// Removes destroyed data keys of the document from history; see
+    /// [`Collection::purge`].
+    pub fn purge(&mut self, id: &str) -> Result<PurgeReport> {
+        self.default_collection().purge(id)
+    }
+
+    /// Runs `f` against a transaction and commits all of its writes as one
+    /// commit. Nothing is written if `f` returns an error.
+    pub fn transaction<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Transaction) -> Result<T>,
+    {
+        self.default_collection().transaction(f)
     }
 
     pub fn list(&self) -> Result<Vec<String>> {
-        self.storage.list()
+        self.default_collection().list()
     }
 
     pub fn find(&self, filter: Option<Filter>) -> Result<Vec<Document>> {
-        let ids = self.list()?;
-        let mut results = Vec::new();
-
-        for id in ids {
-            let doc = self.read(&id)?;
-            if let Some(filter) = &filter {
-                if filter.matches(&doc)? {
-                    results.push(doc);
-                }
-            } else {
-                results.push(doc);
-            }
-        }
+        self.default_collection().find(filter)
+    }
 
-        Ok(results)
+    pub fn find_at(&self, filter: Option<Filter>, rev: &str) -> Result<Vec<Document>> {
+        self.default_collection().find_at(filter, rev)
     }
 }
 
@@ -439,4 +1042,738 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_in_memory_database() -> Result<()> {
+        let key = [3u8; 32];
+        let mut db = GithubDb::in_memory(Some(&key))?;
+
+        let (cert, _) = db.generate_certificate("testuser")?;
+        assert!(db.verify_certificate(&cert)?);
+
+        db.create("a", json!({ "n": 1 }))?;
+        db.update("a", json!({ "n": 2 }))?;
+        db.collection("users")?.create("b", json!({}))?;
+        db.transaction(|tx| {
+            tx.create("c", json!({ "n": 3 }))?;
+            tx.delete("a")
+        })?;
+
+        assert_eq!(db.list()?, vec!["c"]);
+        assert_eq!(db.list_collections()?, vec!["users"]);
+        assert_eq!(db.find(None)?.len(), 1);
+
+        // Versioning needs a git repository
+        assert!(db.history("c").is_err());
+        assert!(db.read_at("c", "HEAD").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bare_repository() -> Result<()> {
+        let dir = tempdir()?;
+        let key = [5u8; 32];
+        {
+            let db = GithubDb::open_bare(dir.path(), Some(&key))?;
+            let (cert, _) = db.generate_certificate("testuser")?;
+            assert!(db.verify_certificate(&cert)?);
+        }
+        {
+            // A fresh handle only sees what was committed
+            let mut db = GithubDb::open_bare(dir.path(), Some(&key))?;
+            assert_eq!(db.list_certificates()?, vec!["testuser"]);
+
+            db.create("a", json!({ "n": 1 }))?;
+            db.update("a", json!({ "n": 2 }))?;
+            db.collection("users")?.create("b", json!({ "n": 3 }))?;
+            db.transaction(|tx| {
+                tx.create("c", json!({}))?;
+                tx.delete("a")
+            })?;
+        }
+
+        // Nothing is checked out, everything lives in the object database
+        assert!(git2::Repository::open(dir.path())?.is_bare());
+        assert!(!dir.path().join("c.json").exists());
+        assert!(!dir.path().join("certs").exists());
+
+        let db = GithubDb::open_bare(dir.path(), Some(&key))?;
+        assert_eq!(db.list()?, vec!["c"]);
+        assert_eq!(db.collection("users")?.read("b")?.data, json!({ "n": 3 }));
+        assert_eq!(db.list_certificates()?, vec!["testuser"]);
+
+        let history = db.history("a")?;
+        assert_eq!(history.len(), 3);
+        assert!(history[0].document.is_none());
+        assert_eq!(db.read_at("a", &history[1].commit.id)?.data, json!({ "n": 2 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_with_remote() -> Result<()> {
+        let remote_dir = tempdir()?;
+        git2::Repository::init_bare(remote_dir.path())?;
+        let remote = RemoteOptions::new(remote_dir.path().to_str().unwrap());
+
+        let dir1 = tempdir()?;
+        let dir2 = tempdir()?;
+        let mut db1 = GithubDb::new(dir1.path(), None)?;
+        let mut db2 = GithubDb::new(dir2.path(), None)?;
+
+        // Pulling from an empty remote is fine
+        assert_eq!(db1.pull(&remote)?, PullOutcome::UpToDate);
+
+        db1.create("a", json!({ "n": 1 }))?;
+        db1.sync(&remote)?;
+        assert_eq!(db2.pull(&remote)?, PullOutcome::FastForward);
+        assert_eq!(db2.read("a")?.data, json!({ "n": 1 }));
+
+        // Both sides move: a plain push is rejected, sync rebases first
+        db2.create("b", json!({ "n": 2 }))?;
+        db2.push(&remote)?;
+        db1.update("a", json!({ "n": 3 }))?;
+        let err = db1.push(&remote).unwrap_err();
+        assert!(matches!(err.downcast_ref::<DbError>(), Some(DbError::NonFastForward(_))));
+
+        assert_eq!(db1.sync(&remote)?, PullOutcome::Rebased(1));
+        assert_eq!(db1.read("b")?.data, json!({ "n": 2 }));
+        assert_eq!(db1.read("a")?.data, json!({ "n": 3 }));
+        assert_eq!(db1.history("a")?[0].commit.message, "Update document a");
+
+        assert_eq!(db2.pull(&remote)?, PullOutcome::FastForward);
+        assert_eq!(db2.read("a")?.data, json!({ "n": 3 }));
+        assert_eq!(db2.pull(&remote)?, PullOutcome::UpToDate);
+
+        // Bare databases sync the same way
+        let bare_dir = tempdir()?;
+        let mut bare = GithubDb::open_bare(bare_dir.path(), None)?;
+        assert_eq!(bare.pull(&remote)?, PullOutcome::FastForward);
+        assert_eq!(bare.list()?.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_merges_concurrent_edits() -> Result<()> {
+        let remote_dir = tempdir()?;
+        git2::Repository::init_bare(remote_dir.path())?;
+        let mut remote = RemoteOptions::new(remote_dir.path().to_str().unwrap());
+
+        let key = [7u8; 32];
+        let dir1 = tempdir()?;
+        let dir2 = tempdir()?;
+        let mut db1 = GithubDb::new(dir1.path(), Some(&key))?;
+        let mut db2 = GithubDb::new(dir2.path(), Some(&key))?;
+
+        db1.create("a", json!({ "name": "Alice", "age": 30 }))?;
+        db1.sync(&remote)?;
+        db2.pull(&remote)?;
+
+        // Different fields of the same encrypted document merge cleanly
+        db1.update("a", json!({ "name": "Alicia", "age": 30 }))?;
+        db1.sync(&remote)?;
+        db2.update("a", json!({ "name": "Alice", "age": 31 }))?;
+        assert_eq!(db2.sync(&remote)?, PullOutcome::Rebased(1));
+        let merged = db2.read("a")?;
+        assert_eq!(merged.data, json!({ "name": "Alicia", "age": 31 }));
+        assert_eq!(merged.version, 3);
+
+        // The same field changed on both sides is reported by default
+        db1.pull(&remote)?;
+        db1.update("a", json!({ "name": "Ally", "age": 31 }))?;
+        db1.sync(&remote)?;
+        db2.update("a", json!({ "name": "Al", "age": 31 }))?;
+        let err = db2.sync(&remote).unwrap_err();
+        match err.downcast_ref::<DbError>() {
+            Some(DbError::MergeConflict(conflicts)) => {
+                assert_eq!(conflicts, &vec![DocumentConflict { path: "a.json".to_string(), fields: vec!["name".to_string()] }]);
+            },
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert_eq!(db2.read("a")?.data["name"], "Al");
+
+        remote.merge_policy = MergePolicy::Theirs;
+        db2.sync(&remote)?;
+        assert_eq!(db2.read("a")?.data["name"], "Ally");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_branches_isolate_and_merge() -> Result<()> {
+        for bare in [false, true] {
+            let dir = tempdir()?;
+            let mut db = if bare {
+                GithubDb::open_bare(dir.path(), None)?
+            } else {
+                GithubDb::new(dir.path(), None)?
+            };
+            db.create("a", json!({ "name": "Alice", "age": 30 }))?;
+            let main = db.current_branch()?;
+
+            db.create_branch("staging", None)?;
+            assert_eq!(db.list_branches()?, vec![main.clone(), "staging".to_string()]);
+            db.checkout("staging")?;
+            db.update("a", json!({ "name": "Alice", "age": 31 }))?;
+            db.create("b", json!({ "n": 1 }))?;
+
+            db.checkout(&main)?;
+            assert_eq!(db.read("a")?.data["age"], 30);
+            assert!(db.read("b").is_err());
+            db.update("a", json!({ "name": "Alicia", "age": 30 }))?;
+
+            let outcome = db.merge_branch("staging", MergePolicy::Fail)?;
+            assert_eq!(outcome, MergeOutcome::Merged(Vec::new()));
+            assert_eq!(db.read("a")?.data, json!({ "name": "Alicia", "age": 31 }));
+            assert_eq!(db.read("b")?.data, json!({ "n": 1 }));
+            assert_eq!(db.merge_branch("staging", MergePolicy::Fail)?, MergeOutcome::UpToDate);
+
+            // Conflicting fields are reported, and settled when a policy is given
+            db.checkout("staging")?;
+            db.update("a", json!({ "name": "Al", "age": 31 }))?;
+            db.checkout(&main)?;
+            db.update("a", json!({ "name": "Ally", "age": 31 }))?;
+            let err = db.merge_branch("staging", MergePolicy::Fail).unwrap_err();
+            assert!(matches!(err.downcast_ref::<DbError>(), Some(DbError::MergeConflict(c)) if c[0].path == "a.json"));
+            assert_eq!(db.read("a")?.data["name"], "Ally");
+
+            let outcome = db.merge_branch("staging", MergePolicy::Theirs)?;
+            assert_eq!(outcome, MergeOutcome::Merged(vec![DocumentConflict {
+                path: "a.json".to_string(),
+                fields: vec!["name".to_string()],
+            }]));
+            assert_eq!(db.read("a")?.data["name"], "Al");
+
+            assert!(db.delete_branch(&main).is_err());
+            db.delete_branch("staging")?;
+            assert!(db.checkout("staging").is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_branch_leaves_head_alone() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        db.create("a", json!({ "n": 1 }))?;
+        let main = db.current_branch()?;
+        db.create_branch("staging", None)?;
+
+        let mut staging = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        staging.set_branch("staging")?;
+        assert_eq!(staging.current_branch()?, "staging");
+        staging.update("a", json!({ "n": 2 }))?;
+        staging.create("b", json!({ "n": 3 }))?;
+        assert_eq!(staging.read("a")?.data, json!({ "n": 2 }));
+        assert_eq!(staging.list()?.len(), 2);
+        assert_eq!(staging.history("a")?.len(), 2);
+        assert_eq!(staging.read_at("a", "HEAD~2")?.data, json!({ "n": 1 }));
+
+        // HEAD and the working tree still belong to main
+        assert_eq!(db.current_branch()?, main);
+        assert!(!dir.path().join("b.json").exists());
+        assert_eq!(db.read("a")?.data, json!({ "n": 1 }));
+        db.create("c", json!({}))?;
+        assert!(staging.read("c").is_err());
+
+        let mut merged = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        merged.set_branch(&main)?;
+        merged.merge_branch("staging", MergePolicy::Fail)?;
+        assert_eq!(merged.read("b")?.data, json!({ "n": 3 }));
+        assert_eq!(db.read("b")?.data, json!({ "n": 3 }));
+
+        assert!(staging.set_branch("missing").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_commits_record_authenticated_author() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), None)?;
+        let (cert, _key) = db.generate_certificate_with_email("alice", "alice@example.com")?;
+        let (other, _key) = db.generate_certificate("bob")?;
+        db.revoke_certificate("bob")?;
+
+        assert!(db.authenticate(&other).is_err());
+        let identity = db.authenticate(&cert)?;
+        assert_eq!(identity, Identity::new("alice", Some("alice@example.com")));
+
+        db.set_committer(Identity::new("ci", Some("ci@example.com")));
+        db.create("doc", json!({ "n": 1 }))?;
+        assert_eq!(db.history("doc")?[0].commit.author, "alice");
+
+        let repo = git2::Repository::open(dir.path())?;
+        let head = repo.head()?.peel_to_commit()?;
+        assert_eq!(head.author().email(), Some("alice@example.com"));
+        assert_eq!(head.committer().name(), Some("ci"));
+
+        // Unauthenticated writes are attributed to the committer
+        let first = repo.find_commit(head.parent_id(0)?)?;
+        assert_eq!(first.author().name(), Some("GithubDB"));
+
+        let (cert, _key) = db.generate_certificate("carol")?;
+        db.authenticate(&cert)?;
+        db.update("doc", json!({ "n": 2 }))?;
+        let head = repo.head()?.peel_to_commit()?;
+        assert_eq!(head.author().email(), Some("carol@github-db"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_signed_commits_verify() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), Some(&[3u8; 32]))?;
+        // Signed by itself, which is fine for a trust root
+        let (cert, key) = db.generate_certificate("alice")?;
+        db.authenticate(&cert)?;
+        db.set_signer(CommitSigner::new(&cert, &key)?);
+        let (other_cert, other_key) = db.generate_certificate("bob")?;
+        let roots = [cert.clone()];
+
+        db.create("a", json!({ "n": 1 }))?;
+        let main = db.current_branch()?;
+        db.create_branch("side", None)?;
+        db.checkout("side")?;
+        db.create("b", json!({ "n": 2 }))?;
+        db.checkout(&main)?;
+        db.update("a", json!({ "n": 3 }))?;
+        db.merge_branch("side", MergePolicy::Fail)?;
+
+        let history = db.verify_history(&roots)?;
+        assert!(history.iter().all(CommitVerification::is_ok));
+        assert_eq!(history[0].status, SignatureStatus::Valid { signer: "alice".to_string() });
+        assert!(db.verify_history(&[]).is_err());
+        // Without alice as a root nothing is trusted
+        assert!(!db.verify_history(std::slice::from_ref(&other_cert))?.last().unwrap().is_ok());
+
+        // Bob was added by alice, so he is trusted; revoking him doesn't
+        // invalidate what he signed before
+        db.set_signer(CommitSigner::new(&other_cert, &other_key)?);
+        db.update("a", json!({ "n": 4 }))?;
+        db.revoke_certificate("bob")?;
+        assert_eq!(db.verify_history(&roots)?[1].status, SignatureStatus::Valid { signer: "bob".to_string() });
+        assert!(db.verify_history(&roots)?.iter().all(CommitVerification::is_ok));
+
+        // Signing with a revoked certificate is caught
+        db.update("a", json!({ "n": 5 }))?;
+        let history = db.verify_history(&roots)?;
+        assert!(matches!(history[0].status, SignatureStatus::Invalid { .. }));
+
+        // So are unsigned commits, whether or not they carry data
+        let mut unsigned = GithubDb::new(dir.path(), Some(&[3u8; 32]))?;
+        unsigned.update("a", json!({ "n": 6 }))?;
+        let history = unsigned.verify_history(&roots)?;
+        assert_eq!(history[0].status, SignatureStatus::Unsigned);
+        assert!(!history[0].is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_commits_outside_certificate_validity_are_invalid() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), Some(&[3u8; 32]))?;
+        let (cert, key) = db.generate_certificate("alice")?;
+        db.authenticate(&cert)?;
+        db.set_signer(CommitSigner::new(&cert, &key)?);
+        db.create("a", json!({ "n": 1 }))?;
+
+        // Certificates are valid for a year from when they are generated
+        let git = db.git()?;
+        let now = git.repo.head()?.peel_to_commit()?.time().seconds();
+        for (seconds, message) in [(now + 400 * 24 * 3600, "From the future"), (now - 24 * 3600, "From the past")] {
+            let head = git.repo.head()?.peel_to_commit()?;
+            let author = git2::Signature::new("alice", "alice@example.com", &git2::Time::new(seconds, 0))?;
+            git.create_commit(Some("HEAD"), &author, &author, message, &head.tree()?, &[&head])?;
+        }
+
+        let history = db.verify_history(&[cert])?;
+        assert!(history[2].is_ok());
+        let reason = |status: &SignatureStatus| match status {
+            SignatureStatus::Invalid { reason } => reason.clone(),
+            other => panic!("unexpected status {:?}", other),
+        };
+        assert!(reason(&history[1].status).contains("expired"));
+        assert!(reason(&history[0].status).contains("not yet valid"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_history_after_key_rotation() -> Result<()> {
+        let dir = tempdir()?;
+        let old = [3u8; 32];
+        let new = [4u8; 32];
+        let mut db = GithubDb::new(dir.path(), Some(&old))?;
+        let (alice, alice_key) = db.generate_certificate("alice")?;
+        db.authenticate(&alice)?;
+        db.set_signer(CommitSigner::new(&alice, &alice_key)?);
+        let (bob, bob_key) = db.generate_certificate("bob")?;
+        db.set_signer(CommitSigner::new(&bob, &bob_key)?);
+        db.create("a", json!({ "n": 1 }))?;
+        db.rotate_key(&KeySource::Raw(old.to_vec()), &KeySource::Raw(new.to_vec()))?;
+
+        // The certificates were added under the old key, which a fresh handle
+        // doesn't have
+        let mut reopened = GithubDb::new(dir.path(), Some(&new))?;
+        let err = reopened.verify_history(std::slice::from_ref(&alice)).unwrap_err();
+        assert!(err.to_string().contains("can't be decrypted"));
+
+        reopened.add_decryption_key(&old)?;
+        assert!(reopened.verify_history(&[alice])?.iter().all(CommitVerification::is_ok));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pushed_certificates_are_not_trusted() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), None)?;
+        let (alice, _) = db.generate_certificate("alice")?;
+
+        // Anyone with push access can store a certificate, signed by itself
+        let (mallory, mallory_key) = db.generate_certificate("mallory")?;
+        db.authenticate(&mallory)?;
+        db.set_signer(CommitSigner::new(&mallory, &mallory_key)?);
+        db.create("a", json!({ "forged": true }))?;
+
+        let history = db.verify_history(&[alice])?;
+        assert!(history[2].is_ok());
+        assert!(matches!(history[1].status, SignatureStatus::Invalid { .. }));
+        assert!(matches!(history[0].status, SignatureStatus::Invalid { .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reads_from_snapshot() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::open_bare(dir.path(), None)?;
+        db.create("a", json!({ "env": "prod", "limit": 10 }))?;
+        db.create("b", json!({ "env": "dev", "limit": 5 }))?;
+        db.create_snapshot("release-1", None)?;
+
+        db.update("a", json!({ "env": "prod", "limit": 20 }))?;
+        db.delete("b")?;
+        db.create("c", json!({ "env": "prod", "limit": 1 }))?;
+
+        assert_eq!(db.read_at("a", "release-1")?.data["limit"], 10);
+        let filter = Filter::Condition(FilterCondition {
+            field: "env".to_string(),
+            op: FilterOp::Eq,
+            value: json!("prod"),
+        });
+        let frozen = db.find_at(Some(filter.clone()), "release-1")?;
+        assert_eq!(frozen.len(), 1);
+        assert_eq!(frozen[0].data["limit"], 10);
+        assert_eq!(db.find_at(None, "release-1")?.len(), 2);
+        assert_eq!(db.find(Some(filter))?.len(), 2);
+
+        assert!(db.find_at(None, "no-such-snapshot").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_changes_since() -> Result<()> {
+        let dir = tempdir()?;
+        let key = [9u8; 32];
+        let mut db = GithubDb::new(dir.path(), Some(&key))?;
+        db.generate_certificate("alice")?;
+        db.create("a", json!({ "n": 1 }))?;
+        let cursor = db.git()?.resolve_commit_id("HEAD")?;
+
+        db.update("a", json!({ "n": 2 }))?;
+        db.collection("users")?.create("u/1", json!({ "name": "Bob" }))?;
+        db.delete("a")?;
+
+        let all = db.changes_since(None, false)?;
+        assert_eq!(all.len(), 4);
+        assert_eq!((all[0].kind, all[0].id.as_str()), (EventKind::Created, "a"));
+        assert!(all[0].after.is_none());
+
+        let events = db.changes_since(Some(&cursor), true)?;
+        let summary: Vec<_> = events.iter()
+            .map(|e| (e.kind, e.collection.as_deref(), e.id.as_str()))
+            .collect();
+        assert_eq!(summary, vec![
+            (EventKind::Updated, None, "a"),
+            (EventKind::Created, Some("users"), "u/1"),
+            (EventKind::Deleted, None, "a"),
+        ]);
+        assert_eq!(events[0].before.as_ref().unwrap().data, json!({ "n": 1 }));
+        assert_eq!(events[0].after.as_ref().unwrap().data, json!({ "n": 2 }));
+        assert!(events[2].after.is_none());
+
+        let head = db.git()?.resolve_commit_id("HEAD")?;
+        assert!(db.changes_since(Some(&head), false)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unlock_with_passphrase() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), None)?;
+        db.unlock(b"a passphrase of any length")?;
+        db.create("a", json!({ "secret": true }))?;
+        assert!(dir.path().join(kdf::KDF_PATH).exists());
+
+        let mut reopened = GithubDb::new(dir.path(), None)?;
+        reopened.unlock(b"a passphrase of any length")?;
+        assert_eq!(reopened.read("a")?.data, json!({ "secret": true }));
+
+        let mut wrong = GithubDb::new(dir.path(), None)?;
+        let err = wrong.unlock(b"another passphrase").unwrap_err().downcast::<DbError>()?;
+        assert!(matches!(err, DbError::WrongKey { .. }));
+        assert!(GithubDb::new(dir.path(), None)?.read("a").unwrap_err().to_string().contains("key is required"));
+
+        // Databases encrypted with a raw key before key derivation existed
+        let legacy_dir = tempdir()?;
+        let key = *b"0123456789abcdef0123456789abcdef";
+        GithubDb::new(legacy_dir.path(), Some(&key))?.create("a", json!({ "n": 1 }))?;
+        let mut legacy = GithubDb::new(legacy_dir.path(), None)?;
+        // A mistyped passphrase neither works nor leaves parameters behind
+        assert!(legacy.unlock(b"mistyped").is_err());
+        assert!(legacy.unlock(b"0123456789abcdef0123456789abcdeX").is_err());
+        assert!(!legacy_dir.path().join(kdf::KDF_PATH).exists());
+        legacy.unlock(&key)?;
+        assert_eq!(legacy.read("a")?.data, json!({ "n": 1 }));
+        assert!(!legacy_dir.path().join(kdf::KDF_PATH).exists());
+
+        // Unlocking an empty database doesn't commit; the parameters go in
+        // with the first write
+        let empty = tempdir()?;
+        let mut db = GithubDb::new(empty.path(), None)?;
+        db.unlock(b"passphrase")?;
+        assert!(db.git()?.head_id()?.is_none());
+        db.create("a", json!({}))?;
+        assert!(db.git()?.read_file_at("HEAD", kdf::KDF_PATH)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_swapped_documents_are_detected() -> Result<()> {
+        for key in [Some([5u8; 32]), None] {
+            let dir = tempdir()?;
+            let mut db = GithubDb::new(dir.path(), key.as_ref().map(|key| &key[..]))?;
+            db.create("a", json!({ "owner": "alice" }))?;
+            db.create("b", json!({ "owner": "bob" }))?;
+            db.collection("users")?.create("a", json!({ "owner": "carol" }))?;
+
+            let a = std::fs::read(dir.path().join("a.json"))?;
+            std::fs::write(dir.path().join("b.json"), &a)?;
+            let err = db.read("b").unwrap_err().downcast::<DbError>()?;
+            assert!(matches!(err, DbError::Tampered(_)), "{:?}", err);
+
+            // Same ID, different collection
+            let path = dir.path().join(db.collection("users")?.storage.relative_path("a")?);
+            std::fs::write(path, &a)?;
+            assert_eq!(db.collection("users")?.read("a").is_err(), key.is_some());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_blame() -> Result<()> {
+        let dir = tempdir()?;
+        let key = [3u8; 32];
+        let mut db = GithubDb::new(dir.path(), Some(&key))?;
+        let (alice, _key) = db.generate_certificate("alice")?;
+        let (bob, _key) = db.generate_certificate("bob")?;
+
+        db.authenticate(&alice)?;
+        db.create("a", json!({ "name": "Alice", "address": { "city": "Oslo", "zip": "0150" } }))?;
+        db.authenticate(&bob)?;
+        db.update("a", json!({ "name": "Alice", "address": { "city": "Bergen", "zip": "0150" } }))?;
+
+        let blame: Vec<_> = db.blame("a")?.into_iter()
+            .map(|field| (field.dotted_path(), field.commit.author))
+            .collect();
+        assert_eq!(blame, vec![
+            ("address.city".to_string(), "bob".to_string()),
+            ("address.zip".to_string(), "alice".to_string()),
+            ("name".to_string(), "alice".to_string()),
+        ]);
+
+        db.delete("a")?;
+        assert!(db.blame("a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_history_and_read_at() -> Result<()> {
+        let dir = tempdir()?;
+        let key = [7u8; 32];
+        let mut db = GithubDb::new(dir.path(), Some(&key))?;
+
+        db.create("doc", json!({ "v": 1 }))?;
+        db.create("other", json!({ "v": 100 }))?;
+        db.update("doc", json!({ "v": 2 }))?;
+        db.delete("doc")?;
+
+        let history = db.history("doc")?;
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].commit.message, "Delete document doc");
+        assert!(history[0].document.is_none());
+        assert_eq!(history[1].document.as_ref().unwrap().data, json!({ "v": 2 }));
+        assert_eq!(history[2].commit.message, "Create document doc");
+        assert_eq!(history[2].commit.author, "GithubDB");
+
+        let first = db.read_at("doc", &history[2].commit.id)?;
+        assert_eq!(first.data, json!({ "v": 1 }));
+        assert!(db.read_at("doc", "HEAD").is_err());
+        assert!(db.history("missing")?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), None)?;
+
+        db.create("doc", json!({ "name": "Alice", "age": 30 }))?;
+        db.update("doc", json!({ "name": "Alice" }))?;
+        let original = db.history("doc")?[1].commit.id.clone();
+
+        let restored = db.restore("doc", &original)?;
+        assert_eq!(restored.data, json!({ "name": "Alice", "age": 30 }));
+        assert_eq!(db.read("doc")?.data, restored.data);
+
+        let history = db.history("doc")?;
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].commit.message, format!("Restore document doc to {}", &original[..7]));
+
+        // Deleted documents can be brought back as well
+        db.delete("doc")?;
+        assert!(db.read("doc").is_err());
+        db.restore("doc", "HEAD~1")?;
+        assert_eq!(db.read("doc")?.data, json!({ "name": "Alice", "age": 30 }));
+
+        // Restoring from a revision where the document did not exist fails
+        db.create("late", json!({}))?;
+        assert!(db.restore("late", &original).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_between_revisions() -> Result<()> {
+        let dir = tempdir()?;
+        let key = [1u8; 32];
+        let mut db = GithubDb::new(dir.path(), Some(&key))?;
+
+        db.create("doc", json!({ "name": "Alice", "age": 30 }))?;
+        db.update("doc", json!({ "name": "Alice", "age": 31, "city": "Boston" }))?;
+
+        let diff = db.diff("doc", Some("HEAD~1"), Some("HEAD"))?;
+        assert_eq!(diff.to_json_patch(), json!([
+            { "op": "replace", "path": "/age", "value": 31 },
+            { "op": "add", "path": "/city", "value": "Boston" },
+        ]));
+
+        // Working copy matches HEAD after a committed write
+        assert!(db.diff("doc", None, None)?.is_empty());
+
+        db.delete("doc")?;
+        let diff = db.diff("doc", Some("HEAD~1"), None)?;
+        assert_eq!(diff.changes.len(), 1);
+        assert_eq!(diff.changes[0].kind, ChangeKind::Removed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_optimistic_concurrency() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), None)?;
+
+        let doc = db.create("doc", json!({ "n": 1 }))?;
+        assert_eq!(doc.version, 1);
+        let doc = db.update("doc", json!({ "n": 2 }))?;
+        assert_eq!(doc.version, 2);
+
+        // A writer holding a stale version is rejected
+        let current = db.update_if("doc", 2, json!({ "n": 3 }))?;
+        assert_eq!(current.version, 3);
+        let err = db.update_if("doc", 2, json!({ "n": 4 })).unwrap_err();
+        match err.downcast_ref::<DbError>() {
+            Some(DbError::Conflict { id, expected, actual }) => {
+                assert_eq!((id.as_str(), *expected, *actual), ("doc", 2, 3));
+            }
+            other => panic!("expected conflict, got {:?}", other),
+        }
+        assert_eq!(db.read("doc")?.data, json!({ "n": 3 }));
+
+        // Restoring counts as a new write
+        assert_eq!(db.restore("doc", "HEAD~2")?.version, 4);
+
+        // Documents written before versioning read as version 0
+        std::fs::write(dir.path().join("legacy.json"), r#"{"id":"legacy","data":{},"created_at":1,"updated_at":1}"#)?;
+        assert_eq!(db.read("legacy")?.version, 0);
+        assert_eq!(db.update_if("legacy", 0, json!({ "n": 1 }))?.version, 1);
+
+        db.transaction(|tx| {
+            assert!(tx.update_if("doc", 3, json!({})).is_err());
+            tx.update_if("doc", 4, json!({ "n": 5 }))?;
+            Ok(())
+        })?;
+        assert_eq!(db.read("doc")?.version, 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_single_commit() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), None)?;
+        db.create("a", json!({ "n": 1 }))?;
+        db.create("b", json!({ "n": 2 }))?;
+
+        db.transaction(|tx| {
+            let a = tx.read("a")?;
+            tx.update("a", json!({ "n": a.data["n"].as_i64().unwrap() + 10 }))?;
+            tx.delete("b")?;
+            tx.create("c", json!({ "n": 3 }))?;
+            assert_eq!(tx.read("c")?.data, json!({ "n": 3 }));
+            assert!(tx.read("b").is_err());
+            Ok(())
+        })?;
+
+        assert_eq!(db.read("a")?.data, json!({ "n": 11 }));
+        assert!(db.read("b").is_err());
+        assert_eq!(db.read("c")?.data, json!({ "n": 3 }));
+
+        let last = &db.history("c")?[0].commit;
+        assert_eq!(last.message, "Transaction (3 operations)\n\n- update a\n- delete b\n- create c");
+        assert_eq!(db.history("a")?[0].commit.id, last.id);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_rollback() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), None)?;
+        db.create("a", json!({ "n": 1 }))?;
+
+        // An error inside the closure discards everything
+        let result = db.transaction(|tx| {
+            tx.update("a", json!({ "n": 2 }))?;
+            tx.create("a", json!({ "n": 3 }))
+        });
+        assert!(result.is_err());
+        assert_eq!(db.read("a")?.data, json!({ "n": 1 }));
+
+        // A failing write rolls back the files already written
+        std::fs::create_dir(dir.path().join("b.json"))?;
+        let result = db.transaction(|tx| {
+            tx.update("a", json!({ "n": 2 }))?;
+            tx.create("b", json!({ "n": 3 }))?;
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(db.read("a")?.data, json!({ "n": 1 }));
+        assert_eq!(db.history("a")?.len(), 1);
+
+        Ok(())
+    }
 }
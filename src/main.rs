@@ -1,6 +1,8 @@
 use anyhow::Result;
-use clap::{Parser, Subcommand};
-use github_db::{Document, GithubDb, Filter, FilterOp, FilterCondition};
+use clap::{Args, Parser, Subcommand};
+use base64::Engine;
+use github_db::{CommitInfo, CommitSigner, CompactOptions, Credentials, Document, DocumentId, GithubDb, Identity, KeySource, Filter, FilterOp, FilterCondition, MergeOutcome, MergePolicy, PullOutcome, RemoteOptions, Revision, SignatureStatus, WatchOptions};
+use serde::Deserialize;
 use serde_json::Value;
 use std::{path::PathBuf, fs, io::{self, Read}, env};
 
@@ -11,10 +13,30 @@ struct Cli {
     #[arg(short, long, default_value = ".github-db", env = "DB_PATH")]
     path: PathBuf,
 
-    /// Encryption key (optional)
-    #[arg(short, long, env = "DB_KEY")]
+    /// Operate on a bare git repository without a working tree
+    #[arg(long, env = "DB_BARE")]
+    bare: bool,
+
+    /// Encryption passphrase (optional); the key is derived from it with scrypt
+    #[arg(short, long, env = "DB_KEY", hide_env_values = true, conflicts_with_all = ["key_hex", "key_base64", "key_file"])]
     key: Option<String>,
 
+    /// Raw 32-byte encryption key as 64 hex digits
+    #[arg(long, env = "DB_KEY_HEX", hide_env_values = true, conflicts_with_all = ["key_base64", "key_file"])]
+    key_hex: Option<String>,
+
+    /// Raw 32-byte encryption key in base64
+    #[arg(long, env = "DB_KEY_BASE64", hide_env_values = true, conflicts_with = "key_file")]
+    key_base64: Option<String>,
+
+    /// File holding the encryption passphrase
+    #[arg(long, env = "DB_KEY_FILE")]
+    key_file: Option<PathBuf>,
+
+    /// Also read files encrypted in formats that aren't bound to their path, to upgrade them with rotate-key
+    #[arg(long, env = "DB_ALLOW_LEGACY_ENCRYPTION")]
+    allow_legacy_encryption: bool,
+
     /// Certificate file for authentication
     #[arg(short, long, env = "DB_CERT")]
     cert: Option<PathBuf>,
@@ -23,10 +45,30 @@ struct Cli {
     #[arg(long, env = "DB_CERT_CONTENT")]
     cert_content: Option<String>,
 
+    /// Name recorded as committer (authors come from the certificate)
+    #[arg(long, env = "DB_COMMITTER_NAME")]
+    committer_name: Option<String>,
+
+    /// Email recorded as committer
+    #[arg(long, env = "DB_COMMITTER_EMAIL")]
+    committer_email: Option<String>,
+
+    /// Private key matching the certificate; when given, every commit is signed
+    #[arg(long, env = "DB_SIGN_KEY")]
+    sign_key: Option<PathBuf>,
+
     /// Read data from stdin instead of command line
     #[arg(long)]
     stdin: bool,
 
+    /// Branch to read from and commit to; HEAD and the working tree are left as they are
+    #[arg(long, env = "DB_BRANCH", global = true)]
+    branch: Option<String>,
+
+    /// Collection to operate on (defaults to the database root)
+    #[arg(long, env = "DB_COLLECTION", global = true)]
+    collection: Option<DocumentId>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -36,29 +78,104 @@ enum Commands {
     /// Create a new document
     Create {
         /// Document ID
-        id: String,
+        id: DocumentId,
         /// JSON data (optional if --stdin is used)
         data: Option<String>,
     },
     /// Read a document
     Read {
         /// Document ID
-        id: String,
+        id: DocumentId,
+        /// Read the document as it was at this snapshot, commit or tag
+        #[arg(long)]
+        at: Option<String>,
     },
     /// Update a document
     Update {
         /// Document ID
-        id: String,
+        id: DocumentId,
         /// JSON data (optional if --stdin is used)
         data: Option<String>,
+        /// Only update if the document is still at this version
+        #[arg(long)]
+        if_match: Option<u64>,
     },
     /// Delete a document
     Delete {
         /// Document ID
-        id: String,
+        id: DocumentId,
+    },
+    /// Delete a document and destroy its data key
+    Shred {
+        /// Document ID
+        id: DocumentId,
+    },
+    /// Remove a shredded document's destroyed data keys from the history of the current branch, rewriting it
+    Purge {
+        /// Document ID
+        id: DocumentId,
     },
     /// List all documents
     List,
+    /// Restore a document to a previous revision
+    Restore {
+        /// Document ID
+        id: DocumentId,
+        /// Commit or tag to restore from
+        rev: String,
+    },
+    /// Show field-level changes between two revisions of a document
+    Diff {
+        /// Document ID
+        id: DocumentId,
+        /// Old revision (defaults to HEAD)
+        #[arg(long)]
+        from: Option<String>,
+        /// New revision (defaults to the working copy)
+        #[arg(long)]
+        to: Option<String>,
+        /// Print the diff as an RFC 6902 JSON Patch
+        #[arg(long)]
+        patch: bool,
+    },
+    /// Show every revision of a document, newest first
+    History {
+        /// Document ID
+        id: DocumentId,
+    },
+    /// Print document changes as NDJSON, oldest first
+    Changes {
+        /// Only changes committed after this commit, tag or snapshot
+        #[arg(long)]
+        since: Option<String>,
+        /// Include the document before and after each change
+        #[arg(long)]
+        values: bool,
+    },
+    /// Keep running and print changes as NDJSON as they are committed
+    Watch {
+        /// Only report documents matching this filter (same format as find)
+        filter: Option<String>,
+        /// Also report changes committed after this commit, tag or snapshot
+        #[arg(long)]
+        since: Option<String>,
+        /// Include the document before and after each change
+        #[arg(long)]
+        values: bool,
+        /// How often to check for new commits (e.g. 5, 30s, 1m)
+        #[arg(long, default_value = "2", value_parser = parse_duration)]
+        interval: std::time::Duration,
+        /// Pull from the remote before every check
+        #[arg(long)]
+        pull: bool,
+        #[command(flatten)]
+        remote: RemoteArgs,
+    },
+    /// Show which commit last changed each value of a document
+    Blame {
+        /// Document ID
+        id: DocumentId,
+    },
     /// Find documents using filters
     Find {
         /// Filter JSON (optional if --stdin is used)
@@ -68,24 +185,284 @@ enum Commands {
         ///   "field": "field.path", "op": "eq|gt|lt|gte|lte|contains|startsWith|endsWith", "value": "..." for condition
         /// }
         filter: Option<String>,
+        /// Search the documents as they were at this snapshot, commit or tag
+        #[arg(long)]
+        at: Option<String>,
+    },
+    /// Apply a list of operations as a single commit
+    Batch {
+        /// NDJSON file with one operation per line (optional if --stdin is used)
+        /// Format: {"op": "create|update|delete", "id": "...", "data": {...}, "if_match": 3}
+        file: Option<PathBuf>,
+    },
+    /// Fetch remote changes, replaying local commits on top of them
+    Pull {
+        #[command(flatten)]
+        remote: RemoteArgs,
+    },
+    /// Push local commits to a remote
+    Push {
+        #[command(flatten)]
+        remote: RemoteArgs,
+    },
+    /// Pull and then push
+    Sync {
+        #[command(flatten)]
+        remote: RemoteArgs,
+    },
+    /// Check that every commit was signed by a trusted certificate
+    VerifyHistory {
+        /// Certificate trusted up front (repeatable); certificates added by commits it signed are trusted in turn
+        #[arg(long, required = true)]
+        trust: Vec<PathBuf>,
+    },
+    /// Drop old revisions from the history of the current branch
+    Compact {
+        /// Squash commits older than this (e.g. 3600, 90m, 12h, 30d)
+        #[arg(long, value_parser = parse_duration)]
+        max_age: Option<std::time::Duration>,
+        /// Keep only the last N revisions of each document
+        #[arg(long)]
+        keep: Option<usize>,
+        /// Also rewrite signed commits, re-signing them with --sign-key (or leaving them unsigned)
+        #[arg(long)]
+        rewrite_signed: bool,
+    },
+    /// Manage named snapshots of the database
+    #[command(subcommand)]
+    Snapshot(SnapshotCommands),
+    /// Manage branches
+    #[command(subcommand)]
+    Branch(BranchCommands),
+    /// Merge another branch into the current branch
+    Merge {
+        /// Branch (or any revision) to merge from
+        source: String,
+        /// How to settle fields changed on both branches: ours, theirs, last-writer-wins or fail
+        #[arg(long, default_value = "fail", env = "DB_MERGE_POLICY")]
+        policy: MergePolicy,
+    },
+    /// List all collections
+    ListCollections,
+    /// Delete a collection and every document in it
+    DropCollection {
+        /// Collection name
+        name: DocumentId,
     },
     /// Generate a new certificate
     GenerateCert {
         /// Username
-        username: String,
+        username: DocumentId,
         /// Output directory for certificate and key
         #[arg(short, long)]
         output: PathBuf,
+        /// Email address recorded in the certificate and used as author email
+        #[arg(long)]
+        email: Option<String>,
+    },
+    /// Re-encrypt all documents and certificates with a new key
+    RotateKey(NewKeyArgs),
+    /// Encrypt each document with its own data key, wrapped by the encryption key
+    EnableDataKeys,
+    /// Move documents and certificates stored under unescaped names, e.g. by older versions, to their escaped names
+    RenameMisnamed {
+        /// Only list the files that would be renamed
+        #[arg(long)]
+        dry_run: bool,
     },
     /// Revoke a certificate
     RevokeCert {
         /// Username
-        username: String,
+        username: DocumentId,
     },
     /// List all valid certificates
     ListCerts,
 }
 
+#[derive(Subcommand)]
+enum BranchCommands {
+    /// Create a branch
+    Create {
+        /// Branch name
+        name: String,
+        /// Commit, tag or branch to start from (defaults to the current commit)
+        #[arg(long)]
+        from: Option<String>,
+    },
+    /// List all branches
+    List,
+    /// Delete a branch
+    Delete {
+        /// Branch name
+        name: String,
+    },
+    /// Make a branch the current branch, checking it out into the working tree
+    Checkout {
+        /// Branch name
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum SnapshotCommands {
+    /// Freeze the current state under a name
+    Create {
+        /// Snapshot name
+        name: String,
+        /// Description stored with the snapshot
+        #[arg(short, long)]
+        message: Option<String>,
+    },
+    /// List all snapshots
+    List,
+    /// Delete a snapshot
+    Delete {
+        /// Snapshot name
+        name: String,
+    },
+}
+
+#[derive(Args)]
+struct NewKeyArgs {
+    /// New encryption passphrase
+    #[arg(long, env = "DB_NEW_KEY", hide_env_values = true, conflicts_with_all = ["new_key_hex", "new_key_base64", "new_key_file"])]
+    new_key: Option<String>,
+    /// New raw 32-byte key as 64 hex digits
+    #[arg(long, env = "DB_NEW_KEY_HEX", hide_env_values = true, conflicts_with_all = ["new_key_base64", "new_key_file"])]
+    new_key_hex: Option<String>,
+    /// New raw 32-byte key in base64
+    #[arg(long, env = "DB_NEW_KEY_BASE64", hide_env_values = true, conflicts_with = "new_key_file")]
+    new_key_base64: Option<String>,
+    /// File holding the new passphrase
+    #[arg(long, env = "DB_NEW_KEY_FILE")]
+    new_key_file: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct RemoteArgs {
+    /// Remote name, URL or path
+    #[arg(long, default_value = "origin", env = "DB_REMOTE")]
+    remote: String,
+    /// Username for HTTPS or SSH authentication
+    #[arg(long, env = "DB_GIT_USERNAME")]
+    username: Option<String>,
+    /// Password or access token for HTTPS remotes
+    #[arg(long, env = "DB_GIT_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// SSH private key for SSH remotes
+    #[arg(long, env = "DB_GIT_SSH_KEY")]
+    ssh_key: Option<PathBuf>,
+    /// Passphrase for the SSH private key
+    #[arg(long, env = "DB_GIT_SSH_PASSPHRASE", hide_env_values = true)]
+    ssh_passphrase: Option<String>,
+    /// How to settle fields changed on both sides: ours, theirs, last-writer-wins or fail
+    #[arg(long, default_value = "fail", env = "DB_MERGE_POLICY")]
+    policy: MergePolicy,
+}
+
+impl RemoteArgs {
+    fn options(&self) -> RemoteOptions {
+        let username = self.username.clone().unwrap_or_else(|| "git".to_string());
+        let credentials = match (&self.ssh_key, &self.token) {
+            (Some(key), _) => Some(Credentials::SshKey {
+                username,
+                private_key: key.clone(),
+                passphrase: self.ssh_passphrase.clone(),
+            }),
+            (None, Some(token)) => Some(Credentials::UserPass { username, password: token.clone() }),
+            (None, None) => None,
+        };
+        RemoteOptions {
+            remote: self.remote.clone(),
+            branch: None,
+            credentials,
+            merge_policy: self.policy,
+        }
+    }
+}
+
+fn print_pull_outcome(outcome: PullOutcome) {
+    match outcome {
+        PullOutcome::UpToDate => println!("Already up to date"),
+        PullOutcome::FastForward => println!("Fast-forwarded to remote changes"),
+        PullOutcome::Rebased(count) => println!("Rebased {} local commit(s) onto remote changes", count),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchOp {
+    Create { id: String, data: Value },
+    Update { id: String, data: Value, if_match: Option<u64> },
+    Delete { id: String },
+}
+
+fn get_batch_ops(file: Option<PathBuf>, stdin: bool) -> Result<Vec<BatchOp>> {
+    let input = if stdin {
+        read_stdin()?
+    } else {
+        let path = file.ok_or_else(|| anyhow::anyhow!("No operations provided. Use --stdin or provide a file"))?;
+        fs::read_to_string(path)?
+    };
+
+    input.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| anyhow::anyhow!("Invalid operation on line {}: {}", i + 1, e))
+        })
+        .collect()
+}
+
+fn decode_hex(value: &str) -> Result<Vec<u8>> {
+    let value = value.trim();
+    if !value.len().is_multiple_of(2) || !value.is_ascii() {
+        anyhow::bail!("Invalid hex key");
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).map_err(|_| anyhow::anyhow!("Invalid hex key")))
+        .collect()
+}
+
+/// The key given by whichever of the passphrase, hex, base64 or file options is set.
+fn key_source(
+    passphrase: Option<&str>,
+    hex: Option<&str>,
+    encoded: Option<&str>,
+    file: Option<&PathBuf>,
+) -> Result<Option<KeySource>> {
+    Ok(if let Some(passphrase) = passphrase {
+        Some(KeySource::Passphrase(passphrase.as_bytes().to_vec()))
+    } else if let Some(hex) = hex {
+        Some(KeySource::Raw(decode_hex(hex)?))
+    } else if let Some(encoded) = encoded {
+        Some(KeySource::Raw(base64::engine::general_purpose::STANDARD.decode(encoded.trim())?))
+    } else if let Some(path) = file {
+        let contents = fs::read(path)?;
+        let passphrase = contents.strip_suffix(b"\n").unwrap_or(&contents);
+        Some(KeySource::Passphrase(passphrase.strip_suffix(b"\r").unwrap_or(passphrase).to_vec()))
+    } else {
+        None
+    })
+}
+
+fn parse_duration(value: &str) -> Result<std::time::Duration> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => anyhow::bail!("Invalid duration unit '{}', expected s, m, h or d", unit),
+    };
+    Ok(std::time::Duration::from_secs(number.parse::<u64>()? * seconds))
+}
+
 fn read_stdin() -> Result<String> {
     let mut buffer = String::new();
     io::stdin().read_to_string(&mut buffer)?;
@@ -165,6 +542,7 @@ fn print_document(doc: &Document) {
         println!("ID: {}", doc.id);
         println!("Created: {}", doc.created_at);
         println!("Updated: {}", doc.updated_at);
+        println!("Version: {}", doc.version);
         println!("Data: {}", serde_json::to_string_pretty(&doc.data).unwrap());
     }
 }
@@ -181,26 +559,85 @@ fn print_documents(docs: &[Document]) {
     }
 }
 
+fn print_history(revisions: &[Revision]) {
+    if env::var("DB_JSON_OUTPUT").is_ok() {
+        println!("{}", serde_json::to_string(revisions).unwrap());
+    } else {
+        for revision in revisions {
+            println!("{}", "-".repeat(40));
+            println!("Commit: {}", revision.commit.id);
+            println!("Author: {}", revision.commit.author);
+            println!("Date: {}", revision.commit.timestamp);
+            println!("Message: {}", revision.commit.message);
+            match &revision.document {
+                Some(doc) => println!("Data: {}", serde_json::to_string_pretty(&doc.data).unwrap()),
+                None => println!("(deleted)"),
+            }
+        }
+    }
+}
+
+/// What a user has to know after history was rewritten.
+fn print_rewrite_notes(lost_signatures: &[CommitInfo], signing: bool, branch: &str) {
+    if !lost_signatures.is_empty() {
+        let now = if signing { "re-signed with your key" } else { "now unsigned" };
+        println!("{} signed commit(s) lost their original signatures and are {}:", lost_signatures.len(), now);
+        for commit in lost_signatures {
+            println!("  {} {} ({})", &commit.id[..7], commit.message.lines().next().unwrap_or_default(), commit.author);
+        }
+    }
+    println!("History was rewritten. Update remotes with `git push --force-with-lease <remote> {}`", branch);
+    println!("and have every clone re-clone or reset to the new branch.");
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
-    let mut db = GithubDb::new(cli.path, cli.key.as_deref().map(str::as_bytes))?;
+    let mut db = if cli.bare {
+        GithubDb::open_bare(&cli.path, None)?
+    } else {
+        GithubDb::new(&cli.path, None)?
+    };
+    if let Some(branch) = &cli.branch {
+        db.set_branch(branch)?;
+    }
+    if let Some(name) = &cli.committer_name {
+        db.set_committer(Identity::new(name, cli.committer_email.as_deref()));
+    }
+    let key = key_source(cli.key.as_deref(), cli.key_hex.as_deref(), cli.key_base64.as_deref(), cli.key_file.as_ref())?;
+
+    if cli.allow_legacy_encryption {
+        db.allow_legacy_encryption();
+    }
+    if let Some(key) = &key {
+        db.set_key(key)?;
+    }
+
+    // Get certificate from file or content
+    let cert_data = match (&cli.cert, &cli.cert_content) {
+        (Some(path), _) => Some(fs::read(path)?),
+        (_, Some(content)) => Some(base64::engine::general_purpose::STANDARD.decode(content)?),
+        _ => None,
+    };
 
     // Handle certificate-based commands separately
     match &cli.command {
-        Commands::GenerateCert { username, output } => {
-            let (cert, key) = db.generate_certificate(username)?;
+        Commands::GenerateCert { username, output, email } => {
+            // Signed by an existing certificate holder if given, else by the new certificate itself
+            if let (Some(cert_data), Some(path)) = (&cert_data, &cli.sign_key) {
+                db.authenticate(cert_data)?;
+                db.set_signer(CommitSigner::new(cert_data, &fs::read(path)?)?);
+            }
+            let (cert, key) = match email {
+                Some(email) => db.generate_certificate_with_email(username.as_str(), email)?,
+                None => db.generate_certificate(username.as_str())?,
+            };
             fs::create_dir_all(output)?;
-            fs::write(output.join(format!("{}.cert", username)), cert)?;
-            fs::write(output.join(format!("{}.key", username)), key)?;
+            fs::write(output.join(format!("{}.cert", username.escaped())), cert)?;
+            fs::write(output.join(format!("{}.key", username.escaped())), key)?;
             println!("Certificate generated for {}", username);
             println!("Files saved in: {}", output.display());
             return Ok(());
         }
-        Commands::RevokeCert { username } => {
-            db.revoke_certificate(username)?;
-            println!("Certificate revoked for {}", username);
-            return Ok(());
-        }
         Commands::ListCerts => {
             let certs = db.list_certificates()?;
             println!("Valid certificates:");
@@ -212,39 +649,246 @@ fn main() -> Result<()> {
         _ => {}
     }
 
-    // Get certificate from file or content
-    let cert_data = match (cli.cert, cli.cert_content) {
-        (Some(path), _) => fs::read(path)?,
-        (_, Some(content)) => base64::decode(content)?,
-        _ => anyhow::bail!("Certificate required. Provide --cert or --cert-content"),
-    };
+    let cert_data = cert_data.ok_or_else(|| anyhow::anyhow!("Certificate required. Provide --cert or --cert-content"))?;
 
-    // Verify certificate for data operations
-    if !db.verify_certificate(&cert_data)? {
-        anyhow::bail!("Invalid or revoked certificate");
+    // Verify certificate for data operations; its holder authors the commits
+    db.authenticate(&cert_data)?;
+    if let Some(path) = &cli.sign_key {
+        db.set_signer(CommitSigner::new(&cert_data, &fs::read(path)?)?);
+    }
+
+    match &cli.command {
+        Commands::RotateKey(new) => {
+            let old = key.ok_or_else(|| anyhow::anyhow!("The current key is required. Provide --key, --key-hex, --key-base64 or --key-file"))?;
+            let new = key_source(new.new_key.as_deref(), new.new_key_hex.as_deref(), new.new_key_base64.as_deref(), new.new_key_file.as_ref())?
+                .ok_or_else(|| anyhow::anyhow!("No new key provided. Use --new-key, --new-key-hex, --new-key-base64 or --new-key-file"))?;
+            let count = db.rotate_key(&old, &new)?;
+            println!("Re-encrypted {} file(s) with the new key", count);
+            return Ok(());
+        }
+        Commands::RevokeCert { username } => {
+            db.revoke_certificate(username.as_str())?;
+            println!("Certificate revoked for {}", username);
+            return Ok(());
+        }
+        Commands::ListCollections => {
+            let collections = db.list_collections()?;
+            if env::var("DB_JSON_OUTPUT").is_ok() {
+                println!("{}", serde_json::to_string(&collections)?);
+            } else {
+                println!("Collections:");
+                for name in collections {
+                    println!("- {}", name);
+                }
+            }
+            return Ok(());
+        }
+        Commands::DropCollection { name } => {
+            db.drop_collection(name.as_str())?;
+            println!("Collection {} dropped successfully", name);
+            return Ok(());
+        }
+        Commands::VerifyHistory { trust } => {
+            let trusted = trust.iter().map(fs::read).collect::<Result<Vec<_>, _>>()?;
+            let history = db.verify_history(&trusted)?;
+            let failures = history.iter().filter(|v| !v.is_ok()).count();
+            if env::var("DB_JSON_OUTPUT").is_ok() {
+                println!("{}", serde_json::to_string(&history)?);
+            } else {
+                for verification in &history {
+                    let status = match &verification.status {
+                        SignatureStatus::Valid { signer } => format!("signed by {}", signer),
+                        SignatureStatus::Unsigned => "unsigned".to_string(),
+                        SignatureStatus::Invalid { reason } => format!("INVALID: {}", reason),
+                    };
+                    let marker = if verification.is_ok() { "ok" } else { "FAIL" };
+                    let commit = &verification.commit;
+                    println!("{:4} {} {} ({})", marker, &commit.id[..7], commit.message.lines().next().unwrap_or_default(), status);
+                }
+            }
+            if failures > 0 {
+                anyhow::bail!("{} of {} commits failed verification", failures, history.len());
+            }
+            return Ok(());
+        }
+        Commands::Changes { since, values } => {
+            let collection = cli.collection.as_ref().map(ToString::to_string);
+            for event in db.changes_since(since.as_deref(), *values)? {
+                if collection.is_none() || event.collection == collection {
+                    println!("{}", serde_json::to_string(&event)?);
+                }
+            }
+            return Ok(());
+        }
+        Commands::Watch { filter, since, values, interval, pull, remote } => {
+            let options = WatchOptions {
+                filter: get_filter(filter.clone(), cli.stdin)?,
+                collection: cli.collection.as_ref().map(ToString::to_string),
+                include_values: *values,
+            };
+            let mut watcher = db.watch(since.as_deref(), options)?;
+            loop {
+                if *pull {
+                    db.pull(&remote.options())?;
+                }
+                for event in watcher.poll(&db)? {
+                    println!("{}", serde_json::to_string(&event)?);
+                }
+                std::thread::sleep(*interval);
+            }
+        }
+        Commands::Compact { max_age, keep, rewrite_signed } => {
+            let options = CompactOptions { max_age: *max_age, keep_revisions: *keep, rewrite_signed: *rewrite_signed };
+            let report = db.compact(&options)?;
+            match &report.backup_ref {
+                Some(backup) => {
+                    println!("Compacted {} commits into {}", report.commits_before, report.commits_after);
+                    println!("Previous history saved as {}", backup);
+                    print_rewrite_notes(&report.lost_signatures, cli.sign_key.is_some(), &db.current_branch()?);
+                }
+                None => println!("Nothing to compact"),
+            }
+            return Ok(());
+        }
+        Commands::EnableDataKeys => {
+            let count = db.enable_data_keys()?;
+            println!("Data keys enabled, {} document(s) converted", count);
+            return Ok(());
+        }
+        Commands::RenameMisnamed { dry_run } => {
+            let files = if *dry_run { db.misnamed_files()? } else { db.rename_misnamed()? };
+            for file in &files {
+                println!("{} -> {}", file.path, file.canonical);
+            }
+            let verb = if *dry_run { "to rename" } else { "renamed" };
+            println!("{} file(s) {}", files.len(), verb);
+            return Ok(());
+        }
+        Commands::Snapshot(SnapshotCommands::Create { name, message }) => {
+            let snapshot = db.create_snapshot(name, message.as_deref())?;
+            println!("Snapshot {} created at {}", snapshot.name, &snapshot.commit[..7]);
+            return Ok(());
+        }
+        Commands::Snapshot(SnapshotCommands::List) => {
+            let snapshots = db.list_snapshots()?;
+            if env::var("DB_JSON_OUTPUT").is_ok() {
+                println!("{}", serde_json::to_string(&snapshots)?);
+            } else {
+                for snapshot in snapshots {
+                    println!("- {} ({}, {}): {}", snapshot.name, &snapshot.commit[..7], snapshot.timestamp, snapshot.message);
+                }
+            }
+            return Ok(());
+        }
+        Commands::Snapshot(SnapshotCommands::Delete { name }) => {
+            db.delete_snapshot(name)?;
+            println!("Snapshot {} deleted", name);
+            return Ok(());
+        }
+        Commands::Branch(BranchCommands::Create { name, from }) => {
+            db.create_branch(name, from.as_deref())?;
+            println!("Branch {} created", name);
+            return Ok(());
+        }
+        Commands::Branch(BranchCommands::List) => {
+            let branches = db.list_branches()?;
+            let current = db.current_branch()?;
+            if env::var("DB_JSON_OUTPUT").is_ok() {
+                println!("{}", serde_json::to_string(&branches)?);
+            } else {
+                for name in branches {
+                    let marker = if name == current { "*" } else { "-" };
+                    println!("{} {}", marker, name);
+                }
+            }
+            return Ok(());
+        }
+        Commands::Branch(BranchCommands::Delete { name }) => {
+            db.delete_branch(name)?;
+            println!("Branch {} deleted", name);
+            return Ok(());
+        }
+        Commands::Branch(BranchCommands::Checkout { name }) => {
+            db.checkout(name)?;
+            println!("Switched to branch {}", name);
+            return Ok(());
+        }
+        Commands::Merge { source, policy } => {
+            match db.merge_branch(source, *policy)? {
+                MergeOutcome::UpToDate => println!("Already up to date"),
+                MergeOutcome::FastForward => println!("Fast-forwarded to {}", source),
+                MergeOutcome::Merged(settled) => {
+                    println!("Merged {} into {}", source, db.current_branch()?);
+                    for conflict in settled {
+                        println!("Resolved conflict: {}", conflict);
+                    }
+                }
+            }
+            return Ok(());
+        }
+        Commands::Pull { remote } => {
+            print_pull_outcome(db.pull(&remote.options())?);
+            return Ok(());
+        }
+        Commands::Push { remote } => {
+            db.push(&remote.options())?;
+            println!("Pushed to {}", remote.remote);
+            return Ok(());
+        }
+        Commands::Sync { remote } => {
+            print_pull_outcome(db.sync(&remote.options())?);
+            println!("Pushed to {}", remote.remote);
+            return Ok(());
+        }
+        _ => {}
     }
 
+    let collection = match &cli.collection {
+        Some(name) => db.collection(name.as_str())?,
+        None => db.default_collection(),
+    };
+
     match cli.command {
         Commands::Create { id, data } => {
             let value = get_json_data(data, cli.stdin)?;
-            let doc = db.create(&id, value)?;
+            let doc = collection.create(id.as_str(), value)?;
             print_document(&doc);
         }
-        Commands::Read { id } => {
-            let doc = db.read(&id)?;
+        Commands::Read { id, at } => {
+            let doc = match at {
+                Some(rev) => collection.read_at(id.as_str(), &rev)?,
+                None => collection.read(id.as_str())?,
+            };
             print_document(&doc);
         }
-        Commands::Update { id, data } => {
+        Commands::Update { id, data, if_match } => {
             let value = get_json_data(data, cli.stdin)?;
-            let doc = db.update(&id, value)?;
+            let doc = match if_match {
+                Some(version) => collection.update_if(id.as_str(), version, value)?,
+                None => collection.update(id.as_str(), value)?,
+            };
             print_document(&doc);
         }
         Commands::Delete { id } => {
-            db.delete(&id)?;
+            collection.delete(id.as_str())?;
             println!("Document {} deleted successfully", id);
         }
+        Commands::Shred { id } => {
+            collection.shred(id.as_str())?;
+            println!("Document {} shredded, its data key is destroyed", id);
+            println!("Earlier commits still hold the key; run `purge {}` to remove it from history", id);
+        }
+        Commands::Purge { id } => {
+            let report = collection.purge(id.as_str())?;
+            if report.rewritten == 0 {
+                println!("No destroyed data keys of {} in history", id);
+            } else {
+                println!("Removed destroyed data keys of {} from {} commit(s)", id, report.rewritten);
+                print_rewrite_notes(&report.lost_signatures, cli.sign_key.is_some(), &db.current_branch()?);
+            }
+        }
         Commands::List => {
-            let docs = db.list()?;
+            let docs = collection.list()?;
             if env::var("DB_JSON_OUTPUT").is_ok() {
                 println!("{}", serde_json::to_string(&docs)?);
             } else {
@@ -254,11 +898,61 @@ fn main() -> Result<()> {
                 }
             }
         }
-        Commands::Find { filter } => {
+        Commands::Restore { id, rev } => {
+            let doc = collection.restore(id.as_str(), &rev)?;
+            print_document(&doc);
+        }
+        Commands::Diff { id, from, to, patch } => {
+            let diff = collection.diff(id.as_str(), from.as_deref(), to.as_deref())?;
+            if patch {
+                println!("{}", serde_json::to_string_pretty(&diff.to_json_patch())?);
+            } else if env::var("DB_JSON_OUTPUT").is_ok() {
+                println!("{}", serde_json::to_string(&diff)?);
+            } else if diff.is_empty() {
+                println!("No changes");
+            } else {
+                print!("{}", diff);
+            }
+        }
+        Commands::History { id } => {
+            let revisions = collection.history(id.as_str())?;
+            print_history(&revisions);
+        }
+        Commands::Blame { id } => {
+            let blame = collection.blame(id.as_str())?;
+            if env::var("DB_JSON_OUTPUT").is_ok() {
+                println!("{}", serde_json::to_string(&blame)?);
+            } else {
+                for field in blame {
+                    let commit = &field.commit;
+                    println!("{} {} {} {}: {}", &commit.id[..7], commit.author, commit.timestamp, field.dotted_path(), field.value);
+                }
+            }
+        }
+        Commands::Find { filter, at } => {
             let filter = get_filter(filter, cli.stdin)?;
-            let docs = db.find(filter)?;
+            let docs = match at {
+                Some(rev) => collection.find_at(filter, &rev)?,
+                None => collection.find(filter)?,
+            };
             print_documents(&docs);
         }
+        Commands::Batch { file } => {
+            let ops = get_batch_ops(file, cli.stdin)?;
+            let count = ops.len();
+            collection.transaction(|tx| {
+                for op in ops {
+                    match op {
+                        BatchOp::Create { id, data } => { tx.create(&id, data)?; }
+                        BatchOp::Update { id, data, if_match: Some(version) } => { tx.update_if(&id, version, data)?; }
+                        BatchOp::Update { id, data, if_match: None } => { tx.update(&id, data)?; }
+                        BatchOp::Delete { id } => tx.delete(&id)?,
+                    }
+                }
+                Ok(())
+            })?;
+            println!("Batch applied: {} operations", count);
+        }
         _ => unreachable!(),
     }
 
@@ -0,0 +1,307 @@
+use crate::{DbError, Document, GitManager};
+use anyhow::Result;
+use git2::{Index, Oid};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// How to settle a field (or a whole document) that both sides changed in
+/// different ways. Changes that don't overlap are always merged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// Keep the local value.
+    Ours,
+    /// Keep the incoming value.
+    Theirs,
+    /// Keep the value from the document with the newer `updated_at`.
+    LastWriterWins,
+    /// Refuse to merge and report the conflicting documents.
+    #[default]
+    Fail,
+}
+
+impl FromStr for MergePolicy {
+    type Err = DbError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ours" => Ok(Self::Ours),
+            "theirs" => Ok(Self::Theirs),
+            "last-writer-wins" => Ok(Self::LastWriterWins),
+            "fail" => Ok(Self::Fail),
+            _ => Err(DbError::InvalidArgument(format!(
+                "Unknown merge policy '{}', expected ours, theirs, last-writer-wins or fail", s
+            ))),
+        }
+    }
+}
+
+/// A document that could not be merged automatically.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DocumentConflict {
+    /// Path of the document file in the repository.
+    pub path: String,
+    /// Dotted paths of the conflicting fields; `(deleted)` when one side
+    /// deleted the document and the other changed it.
+    pub fields: Vec<String>,
+}
+
+impl fmt::Display for DocumentConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.fields.is_empty() {
+            write!(f, "{}", self.path)
+        } else {
+            write!(f, "{} ({})", self.path, self.fields.join(", "))
+        }
+    }
+}
+
+pub(crate) fn format_conflicts(conflicts: &[DocumentConflict]) -> String {
+    conflicts.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+}
+
+#[derive(Debug, Clone)]
+pub struct DocumentMerge {
+    /// The merged document, or `None` if the merge result is a deletion.
+    pub document: Option<Document>,
+    /// Fields both sides changed differently, already settled by the policy
+    /// unless it is [`MergePolicy::Fail`].
+    pub conflicts: Vec<String>,
+}
+
+/// Three-way merges two versions of a document field by field. `None`
+/// stands for a document that doesn't exist on that side.
+pub fn merge_documents(
+    base: Option<&Document>,
+    ours: Option<&Document>,
+    theirs: Option<&Document>,
+    policy: MergePolicy,
+) -> DocumentMerge {
+    let (ours, theirs) = match (ours, theirs) {
+        (Some(ours), Some(theirs)) => (ours, theirs),
+        (None, None) => return DocumentMerge { document: None, conflicts: Vec::new() },
+        (ours, theirs) => return merge_deletion(base, ours, theirs, policy),
+    };
+
+    let prefer_ours = match policy {
+        MergePolicy::Ours | MergePolicy::Fail => true,
+        MergePolicy::Theirs => false,
+        MergePolicy::LastWriterWins => ours.updated_at >= theirs.updated_at,
+    };
+
+    let mut conflicts = Vec::new();
+    let data = merge_values(
+        &mut Vec::new(),
+        base.map(|doc| &doc.data),
+        Some(&ours.data),
+        Some(&theirs.data),
+        prefer_ours,
+        &mut conflicts,
+    );
+
+    let document = Document {
+        id: ours.id.clone(),
+        data: data.unwrap_or(Value::Null),
+        created_at: ours.created_at.min(theirs.created_at),
+        updated_at: ours.updated_at.max(theirs.updated_at),
+        version: ours.version.max(theirs.version) + 1,
+    };
+    DocumentMerge { document: Some(document), conflicts }
+}
+
+/// One side deleted the document. If the other side left it untouched the
+/// deletion wins; otherwise it's a conflict. Deletions carry no timestamp,
+/// so last-writer-wins keeps the changed document.
+fn merge_deletion(
+    base: Option<&Document>,
+    ours: Option<&Document>,
+    theirs: Option<&Document>,
+    policy: MergePolicy,
+) -> DocumentMerge {
+    let (changed, ours_deleted) = match (ours, theirs) {
+        (Some(doc), None) => (doc, false),
+        (None, Some(doc)) => (doc, true),
+        _ => unreachable!(),
+    };
+
+    if base.map(|base| &base.data) == Some(&changed.data) {
+        return DocumentMerge { document: None, conflicts: Vec::new() };
+    }
+
+    let keep = match policy {
+        MergePolicy::Ours => !ours_deleted,
+        MergePolicy::Theirs => ours_deleted,
+        MergePolicy::LastWriterWins | MergePolicy::Fail => true,
+    };
+    // A document added on one side only isn't a conflict at all
+    let conflicts = if base.is_some() { vec!["(deleted)".to_string()] } else { Vec::new() };
+    DocumentMerge {
+        document: keep.then(|| changed.clone()),
+        conflicts,
+    }
+}
+
+fn merge_values(
+    path: &mut Vec<String>,
+    base: Option<&Value>,
+    ours: Option<&Value>,
+    theirs: Option<&Value>,
+    prefer_ours: bool,
+    conflicts: &mut Vec<String>,
+) -> Option<Value> {
+    if ours == theirs || theirs == base {
+        return ours.cloned();
+    }
+    if ours == base {
+        return theirs.cloned();
+    }
+
+    if let (Some(Value::Object(ours)), Some(Value::Object(theirs))) = (ours, theirs) {
+        let base = base.and_then(Value::as_object);
+        let mut merged = Map::new();
+        for key in ours.keys().chain(theirs.keys().filter(|key| !ours.contains_key(*key))) {
+            path.push(key.clone());
+            let value = merge_values(
+                path,
+                base.and_then(|base| base.get(key)),
+                ours.get(key),
+                theirs.get(key),
+                prefer_ours,
+                conflicts,
+            );
+            path.pop();
+            if let Some(value) = value {
+                merged.insert(key.clone(), value);
+            }
+        }
+        return Some(Value::Object(merged));
+    }
+
+    conflicts.push(if path.is_empty() { "(root)".to_string() } else { path.join(".") });
+    if prefer_ours { ours.cloned() } else { theirs.cloned() }
+}
+
+/// The commits the base, ours and theirs versions of a conflicting path come
+/// from, for reading other files as of each side.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MergeCommits {
+    pub base: Option<Oid>,
+    pub ours: Oid,
+    pub theirs: Oid,
+}
+
+/// Settles the conflicts git couldn't resolve in `index`, given the blob
+/// contents of base, ours and theirs for a path.
+pub(crate) type Resolver<'a> = dyn FnMut(&str, &MergeCommits, Option<&[u8]>, Option<&[u8]>, Option<&[u8]>) -> Result<Option<Vec<u8>>> + 'a;
+
+impl GitManager {
+    /// Replaces every conflict in `index` with the resolver's result. Paths
+    /// the resolver reports as [`DbError::MergeConflict`] are collected and
+    /// returned together.
+    pub(crate) fn resolve_conflicts(&self, index: &mut Index, commits: &MergeCommits, resolver: &mut Resolver) -> Result<()> {
+        let conflicts = index.conflicts()?.collect::<Result<Vec<_>, _>>()?;
+        let mut unresolved = Vec::new();
+
+        for conflict in conflicts {
+            let content = |entry: &Option<git2::IndexEntry>| -> Result<Option<Vec<u8>>> {
+                match entry {
+                    Some(entry) => Ok(Some(self.repo.find_blob(entry.id)?.content().to_vec())),
+                    None => Ok(None),
+                }
+            };
+            let base = content(&conflict.ancestor)?;
+            let ours = content(&conflict.our)?;
+            let theirs = content(&conflict.their)?;
+
+            let mut entry = match conflict.our.or(conflict.their).or(conflict.ancestor) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let path = String::from_utf8_lossy(&entry.path).into_owned();
+
+            match resolver(&path, commits, base.as_deref(), ours.as_deref(), theirs.as_deref()) {
+                Ok(resolved) => {
+                    index.remove_path(Path::new(&path))?;
+                    if let Some(data) = resolved {
+                        entry.id = self.repo.blob(&data)?;
+                        entry.file_size = data.len() as u32;
+                        // Clear the conflict stage
+                        entry.flags &= !0x3000;
+                        index.add(&entry)?;
+                    }
+                },
+                Err(e) => match e.downcast::<DbError>() {
+                    Ok(DbError::MergeConflict(conflicts)) => unresolved.extend(conflicts),
+                    Ok(e) => return Err(e.into()),
+                    Err(e) => return Err(e),
+                },
+            }
+        }
+
+        if unresolved.is_empty() {
+            Ok(())
+        } else {
+            Err(DbError::MergeConflict(unresolved).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn doc(data: Value, updated_at: u64, version: u64) -> Document {
+        Document { id: "a".to_string(), data, created_at: 1, updated_at, version }
+    }
+
+    #[test]
+    fn test_non_overlapping_changes_merge() {
+        let base = doc(json!({ "name": "Alice", "age": 30, "tags": ["a"] }), 1, 1);
+        let ours = doc(json!({ "name": "Alicia", "age": 30, "tags": ["a"] }), 2, 2);
+        let theirs = doc(json!({ "name": "Alice", "age": 31, "tags": ["a"], "city": "Oslo" }), 3, 2);
+
+        let merge = merge_documents(Some(&base), Some(&ours), Some(&theirs), MergePolicy::Fail);
+        assert!(merge.conflicts.is_empty());
+        let merged = merge.document.unwrap();
+        assert_eq!(merged.data, json!({ "name": "Alicia", "age": 31, "tags": ["a"], "city": "Oslo" }));
+        assert_eq!(merged.version, 3);
+        assert_eq!(merged.updated_at, 3);
+    }
+
+    #[test]
+    fn test_conflicting_fields_follow_policy() {
+        let base = doc(json!({ "address": { "city": "Oslo", "zip": "0150" } }), 1, 1);
+        let ours = doc(json!({ "address": { "city": "Bergen", "zip": "0150" } }), 5, 2);
+        let theirs = doc(json!({ "address": { "city": "Tromsø", "zip": "9008" } }), 3, 2);
+
+        let merge = |policy| merge_documents(Some(&base), Some(&ours), Some(&theirs), policy);
+        assert_eq!(merge(MergePolicy::Fail).conflicts, vec!["address.city"]);
+        assert_eq!(merge(MergePolicy::Ours).document.unwrap().data, json!({ "address": { "city": "Bergen", "zip": "9008" } }));
+        assert_eq!(merge(MergePolicy::Theirs).document.unwrap().data, json!({ "address": { "city": "Tromsø", "zip": "9008" } }));
+        assert_eq!(merge(MergePolicy::LastWriterWins).document.unwrap().data, json!({ "address": { "city": "Bergen", "zip": "9008" } }));
+    }
+
+    #[test]
+    fn test_delete_against_change() {
+        let base = doc(json!({ "n": 1 }), 1, 1);
+        let changed = doc(json!({ "n": 2 }), 2, 2);
+
+        // Deleting an untouched document merges cleanly
+        let merge = merge_documents(Some(&base), None, Some(&base), MergePolicy::Fail);
+        assert!(merge.document.is_none() && merge.conflicts.is_empty());
+
+        let merge = merge_documents(Some(&base), None, Some(&changed), MergePolicy::Fail);
+        assert_eq!(merge.conflicts, vec!["(deleted)"]);
+        assert!(merge_documents(Some(&base), None, Some(&changed), MergePolicy::Ours).document.is_none());
+        assert!(merge_documents(Some(&base), None, Some(&changed), MergePolicy::Theirs).document.is_some());
+    }
+
+    #[test]
+    fn test_policy_from_str() {
+        assert_eq!("last-writer-wins".parse::<MergePolicy>().unwrap(), MergePolicy::LastWriterWins);
+        assert!(matches!("newest".parse::<MergePolicy>(), Err(DbError::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,243 @@
+use crate::merge::{MergeCommits, Resolver};
+use crate::{DbError, GitManager, MergePolicy};
+use anyhow::Result;
+use git2::{Cred, CredentialType, FetchOptions, Oid, PushOptions, Remote, RemoteCallbacks, Sort};
+use std::cell::RefCell;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone)]
+pub enum Credentials {
+    /// Username and password (or personal access token) for HTTPS remotes.
+    UserPass { username: String, password: String },
+    /// An SSH private key file.
+    SshKey {
+        username: String,
+        private_key: PathBuf,
+        passphrase: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct RemoteOptions {
+    /// Name of a configured remote, or a URL or path to use directly.
+    pub remote: String,
+    /// Branch to synchronize. Defaults to the branch HEAD points at.
+    pub branch: Option<String>,
+    /// Without credentials, SSH remotes fall back to the SSH agent.
+    pub credentials: Option<Credentials>,
+    /// How to settle documents changed on both sides.
+    pub merge_policy: MergePolicy,
+}
+
+impl RemoteOptions {
+    pub fn new(remote: &str) -> Self {
+        Self {
+            remote: remote.to_string(),
+            branch: None,
+            credentials: None,
+            merge_policy: MergePolicy::default(),
+        }
+    }
+}
+
+impl Default for RemoteOptions {
+    fn default() -> Self {
+        Self::new("origin")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PullOutcome {
+    /// The remote had nothing new.
+    UpToDate,
+    /// Local history was behind the remote and was moved forward.
+    FastForward,
+    /// Local commits were replayed on top of the remote; holds their count.
+    Rebased(usize),
+}
+
+/// Where fetched branch tips are recorded, independent of configured remotes.
+const FETCH_REF_PREFIX: &str = "refs/github-db/fetch/";
+
+impl GitManager {
+    fn callbacks(credentials: Option<&Credentials>) -> RemoteCallbacks<'_> {
+        let mut callbacks = RemoteCallbacks::new();
+        let mut attempts = 0;
+        callbacks.credentials(move |_url, username_from_url, allowed| {
+            // libgit2 keeps asking while credentials are rejected
+            attempts += 1;
+            if attempts > 1 {
+                return Err(git2::Error::from_str("Authentication failed"));
+            }
+            match credentials {
+                Some(Credentials::UserPass { username, password }) => Cred::userpass_plaintext(username, password),
+                Some(Credentials::SshKey { username, private_key, passphrase }) => {
+                    Cred::ssh_key(username, None, private_key, passphrase.as_deref())
+                },
+                None if allowed.contains(CredentialType::SSH_KEY) => {
+                    Cred::ssh_key_from_agent(username_from_url.unwrap_or("git"))
+                },
+                None => Cred::default(),
+            }
+        });
+        callbacks
+    }
+
+    fn find_remote(&self, options: &RemoteOptions) -> Result<Remote<'_>> {
+        match self.repo.find_remote(&options.remote) {
+            Ok(remote) => Ok(remote),
+            Err(_) => Ok(self.repo.remote_anonymous(&options.remote)?),
+        }
+    }
+
+    fn sync_branch(&self, options: &RemoteOptions) -> Result<String> {
+        match &options.branch {
+            Some(branch) => Ok(branch.clone()),
+            None => self.current_branch(),
+        }
+    }
+
+    /// Downloads the remote branch and returns its tip, or `None` if the
+    /// remote doesn't have the branch yet.
+    fn fetch(&self, options: &RemoteOptions, branch: &str) -> Result<Option<Oid>> {
+        let fetch_ref = format!("{}{}", FETCH_REF_PREFIX, branch);
+        if let Ok(mut stale) = self.repo.find_reference(&fetch_ref) {
+            stale.delete()?;
+        }
+
+        let mut remote = self.find_remote(options)?;
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(Self::callbacks(options.credentials.as_ref()));
+        let refspec = format!("+refs/heads/{}:{}", branch, fetch_ref);
+        remote.fetch(&[&refspec], Some(&mut fetch_options), None)?;
+
+        Ok(self.repo.refname_to_id(&fetch_ref).ok())
+    }
+
+    /// Fetches the remote branch and brings the local branch up to date with
+    /// it, replaying local commits on top of the remote ones if both moved.
+    /// Files changed on both sides are handed to `resolver`.
+    pub(crate) fn pull(&self, options: &RemoteOptions, resolver: &mut Resolver) -> Result<PullOutcome> {
+        let branch = self.sync_branch(options)?;
+        let remote_tip = match self.fetch(options, &branch)? {
+            Some(oid) => oid,
+            None => return Ok(PullOutcome::UpToDate),
+        };
+
+        let local_ref = format!("refs/heads/{}", branch);
+        let (new_tip, outcome) = match self.repo.refname_to_id(&local_ref).ok() {
+            None => (remote_tip, PullOutcome::FastForward),
+            Some(local) if local == remote_tip => return Ok(PullOutcome::UpToDate),
+            Some(local) => {
+                let base = self.repo.merge_base(local, remote_tip)?;
+                if base == remote_tip {
+                    return Ok(PullOutcome::UpToDate);
+                } else if base == local {
+                    (remote_tip, PullOutcome::FastForward)
+                } else {
+                    let (tip, count) = self.rebase_onto(local, remote_tip, resolver)?;
+                    (tip, PullOutcome::Rebased(count))
+                }
+            },
+        };
+
+        self.repo.reference(&local_ref, new_tip, true, "github-db: pull")?;
+        if self.current_branch()? == branch {
+            self.checkout_worktree()?;
+        }
+        Ok(outcome)
+    }
+
+    /// Replays the local commits that aren't in `upstream` on top of it,
+    /// oldest first, keeping their authors. Merge commits are replayed as
+    /// their changes relative to the first parent.
+    fn rebase_onto(&self, local: Oid, upstream: Oid, resolver: &mut Resolver) -> Result<(Oid, usize)> {
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        revwalk.simplify_first_parent()?;
+        revwalk.push(local)?;
+        revwalk.hide(upstream)?;
+        let commits = revwalk.collect::<Result<Vec<_>, _>>()?;
+
+        let committer = self.committer()?;
+        let mut tip = self.repo.find_commit(upstream)?;
+        let mut count = 0;
+        for oid in commits {
+            let commit = self.repo.find_commit(oid)?;
+            let mainline = if commit.parent_count() > 1 { 1 } else { 0 };
+            let mut index = self.repo.cherrypick_commit(&commit, &tip, mainline, None)?;
+            if index.has_conflicts() {
+                // git's "ours" is the commit being built on, "theirs" the
+                // local commit being replayed
+                let commits = MergeCommits { base: commit.parent_id(0).ok(), ours: commit.id(), theirs: tip.id() };
+                self.resolve_conflicts(&mut index, &commits, &mut |path, commits, base, upstream, local| {
+                    resolver(path, commits, base, local, upstream)
+                })?;
+            }
+
+            let tree_id = index.write_tree_to(&self.repo)?;
+            // The remote already contains an identical change
+            if tree_id == tip.tree_id() {
+                continue;
+            }
+            let tree = self.repo.find_tree(tree_id)?;
+            let message = commit.message().unwrap_or_default();
+            let oid = self.create_commit(None, &commit.author(), &committer, message, &tree, &[&tip])?;
+            tip = self.repo.find_commit(oid)?;
+            count += 1;
+        }
+
+        Ok((tip.id(), count))
+    }
+
+    /// Pushes the local branch. Fails with [`DbError::NonFastForward`] if the
+    /// remote has commits that aren't present locally.
+    pub fn push(&self, options: &RemoteOptions) -> Result<()> {
+        let branch = self.sync_branch(options)?;
+        let local_ref = format!("refs/heads/{}", branch);
+        let local = match self.repo.refname_to_id(&local_ref) {
+            Ok(oid) => oid,
+            // Nothing committed yet, nothing to push
+            Err(_) => return Ok(()),
+        };
+
+        if let Some(remote_tip) = self.fetch(options, &branch)? {
+            if remote_tip == local {
+                return Ok(());
+            }
+            if !self.repo.graph_descendant_of(local, remote_tip)? {
+                return Err(DbError::NonFastForward(branch).into());
+            }
+        }
+
+        let rejected = RefCell::new(None);
+        let mut callbacks = Self::callbacks(options.credentials.as_ref());
+        callbacks.push_update_reference(|_refname, status| {
+            if let Some(message) = status {
+                *rejected.borrow_mut() = Some(message.to_string());
+            }
+            Ok(())
+        });
+        let mut push_options = PushOptions::new();
+        push_options.remote_callbacks(callbacks);
+
+        let mut remote = self.find_remote(options)?;
+        remote.push(&[format!("{0}:{0}", local_ref)], Some(&mut push_options))?;
+        drop(push_options);
+
+        match rejected.into_inner() {
+            Some(message) if message.contains("fast-forward") || message.contains("fetch first") => {
+                Err(DbError::NonFastForward(branch).into())
+            },
+            Some(message) => Err(DbError::Git(format!("Push rejected: {}", message)).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Pulls and then pushes, leaving local and remote branches identical.
+    pub(crate) fn sync(&self, options: &RemoteOptions, resolver: &mut Resolver) -> Result<PullOutcome> {
+        let outcome = self.pull(options, resolver)?;
+        self.push(options)?;
+        Ok(outcome)
+    }
+}
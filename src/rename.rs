@@ -0,0 +1,152 @@
+use crate::backend::{join, BatchOp};
+use crate::cert::CERTS_DIR;
+use crate::datakey::data_key_path;
+use crate::storage::COLLECTIONS_DIR;
+use crate::{DbError, DocumentId, GithubDb, Storage};
+use anyhow::Result;
+use serde::Serialize;
+
+/// A document, collection or certificate file stored under a name that isn't
+/// the canonical escaped form of its ID, and the path it belongs at.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MisnamedFile {
+    pub path: String,
+    pub canonical: String,
+}
+
+/// The canonical form of a stored name. Names that aren't a canonical escaped
+/// ID were written by versions that stored IDs unescaped, and are the ID
+/// itself.
+fn canonical_name(name: &str) -> Result<String> {
+    match DocumentId::from_escaped(name) {
+        Ok(_) => Ok(name.to_string()),
+        Err(_) => Ok(DocumentId::new(name)?.escaped()),
+    }
+}
+
+impl GithubDb {
+    /// Files whose names aren't canonical escaped IDs, such as documents
+    /// written before IDs were escaped. They are left out of listings and
+    /// queries until [`GithubDb::rename_misnamed`] moves them.
+    pub fn misnamed_files(&self) -> Result<Vec<MisnamedFile>> {
+        let backend = self.storage.backend();
+        let mut dirs = vec![(String::new(), String::new())];
+        for name in backend.list_dirs(COLLECTIONS_DIR)? {
+            dirs.push((join(COLLECTIONS_DIR, &name), join(COLLECTIONS_DIR, &canonical_name(&name)?)));
+        }
+
+        let mut files = Vec::new();
+        for (dir, canonical_dir) in dirs {
+            for file_name in backend.list(&dir)? {
+                if let Some(stem) = file_name.strip_suffix(".json") {
+                    let canonical = join(&canonical_dir, &format!("{}.json", canonical_name(stem)?));
+                    files.push(MisnamedFile { path: join(&dir, &file_name), canonical });
+                }
+            }
+        }
+        for file_name in backend.list(CERTS_DIR)? {
+            for extension in [".cert", ".key"] {
+                if let Some(stem) = file_name.strip_suffix(extension) {
+                    let canonical = join(CERTS_DIR, &format!("{}{}", canonical_name(stem)?, extension));
+                    files.push(MisnamedFile { path: join(CERTS_DIR, &file_name), canonical });
+                }
+            }
+        }
+        files.retain(|file| file.path != file.canonical);
+        Ok(files)
+    }
+
+    /// Moves every file reported by [`GithubDb::misnamed_files`] to its
+    /// canonical name, re-encrypting it for the new path, and commits the
+    /// result. Nothing is moved if any target already exists.
+    pub fn rename_misnamed(&mut self) -> Result<Vec<MisnamedFile>> {
+        let backend = self.storage.backend().clone();
+        let files = self.misnamed_files()?;
+        if files.is_empty() {
+            return Ok(files);
+        }
+
+        let mut ops: Vec<BatchOp> = Vec::new();
+        for file in &files {
+            if backend.exists(&file.canonical)? || files.iter().filter(|other| other.canonical == file.canonical).count() > 1 {
+                return Err(DbError::Storage(format!("Can't rename {} to {}: the name is taken", file.path, file.canonical)).into());
+            }
+            let data = backend.read(&file.path)?;
+            ops.push((file.path.clone(), None));
+            if file.path.ends_with(".json") {
+                let doc = self.decode(&file.path, data)?;
+                if Storage::parse_path(&file.canonical).map(|(_, id)| id) != Some(doc.id.clone()) {
+                    return Err(DbError::Tampered(format!("{} holds document {}", file.path, doc.id)).into());
+                }
+                let key_path = data_key_path(&file.path);
+                if backend.exists(&key_path)? {
+                    ops.push((key_path, None));
+                }
+                ops.extend(self.encode(&file.canonical, &doc)?);
+            } else {
+                let plaintext = self.cert_manager.decrypt_data(&file.path, &data)?;
+                ops.push((file.canonical.clone(), Some(self.cert_manager.encrypt_data(&file.canonical, &plaintext)?)));
+            }
+        }
+        backend.write_batch(&ops)?;
+        self.commit(&format!("Rename {} file(s) to their escaped names", files.len()))?;
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Document;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_rename_misnamed() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        db.create("plain", json!({}))?;
+        db.generate_certificate("bob@example.com")?;
+
+        // Written unescaped, the way IDs used to be stored
+        let backend = db.storage.backend().clone();
+        for (path, id) in [("my doc.json", "my doc"), ("collections/my stuff/a+b.json", "a+b")] {
+            let doc = Document { id: id.to_string(), data: json!({ "id": id }), created_at: 1, updated_at: 1, version: 1 };
+            backend.write_batch(&db.encode(path, &doc)?)?;
+        }
+        let cert = backend.read("certs/bob%40example.com.cert")?;
+        let plaintext = db.cert_manager.decrypt_data("certs/bob%40example.com.cert", &cert)?;
+        backend.delete("certs/bob%40example.com.cert")?;
+        backend.write("certs/bob@example.com.cert", &db.cert_manager.encrypt_data("certs/bob@example.com.cert", &plaintext)?)?;
+        db.commit("Legacy names")?;
+        assert_eq!(db.list()?, vec!["plain".to_string()]);
+        assert!(db.list_certificates()?.is_empty());
+
+        let expected = vec![
+            MisnamedFile { path: "my doc.json".to_string(), canonical: "my%20doc.json".to_string() },
+            MisnamedFile { path: "collections/my stuff/a+b.json".to_string(), canonical: "collections/my%20stuff/a%2Bb.json".to_string() },
+            MisnamedFile { path: "certs/bob@example.com.cert".to_string(), canonical: "certs/bob%40example.com.cert".to_string() },
+        ];
+        assert_eq!(db.misnamed_files()?, expected);
+        assert_eq!(db.rename_misnamed()?, expected);
+        assert!(db.misnamed_files()?.is_empty());
+
+        assert_eq!(db.read("my doc")?.data, json!({ "id": "my doc" }));
+        assert_eq!(db.collection("my stuff")?.read("a+b")?.data, json!({ "id": "a+b" }));
+        assert_eq!(db.list_certificates()?, vec!["bob@example.com".to_string()]);
+        assert!(!dir.path().join("my doc.json").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_refuses_to_overwrite() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), None)?;
+        db.create("a b", json!({ "n": 1 }))?;
+        std::fs::write(dir.path().join("a b.json"), r#"{"id":"a b","data":{"n":2},"created_at":1,"updated_at":1}"#)?;
+
+        assert!(db.rename_misnamed().is_err());
+        assert_eq!(db.read("a b")?.data, json!({ "n": 1 }));
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+use crate::backend::BatchOp;
+use crate::cert::CERTS_DIR;
+use crate::datakey::data_key_paths;
+use crate::kdf::{KdfParams, KeySource, KDF_PATH, PENDING_KDF_PATH};
+use crate::{Crypto, DbError, Envelope, GithubDb};
+use anyhow::Result;
+
+impl GithubDb {
+    /// Re-encrypts every document and every file in `certs/` from the `old`
+    /// key to the `new` one and commits the result. Each file is checked to
+    /// decrypt with the new key before any is written, and all of them are
+    /// then written with a single [`StorageBackend::write_batch`]. Returns
+    /// the number of files re-encrypted. A new passphrase gets a fresh salt.
+    ///
+    /// Documents encrypted with data keys aren't touched; only their wrapped
+    /// keys are re-encrypted. With [`GithubDb::allow_legacy_encryption`],
+    /// files in the legacy formats are upgraded to ones bound to their path.
+    ///
+    /// If a rotation is interrupted while the batch is applied, running it
+    /// again with the same keys finishes it: files whose envelope already
+    /// names the new key are left alone. Earlier revisions and other
+    /// branches stay encrypted with the old key, which this handle keeps for
+    /// reading them.
+    ///
+    /// [`StorageBackend::write_batch`]: crate::StorageBackend::write_batch
+    pub fn rotate_key(&mut self, old: &KeySource, new: &KeySource) -> Result<usize> {
+        let backend = self.storage.backend().clone();
+
+        let old_key = match old {
+            KeySource::Raw(key) => key.clone(),
+            KeySource::Passphrase(passphrase) => match self.committed_kdf_params()? {
+                Some(params) => params.derive(passphrase)?.to_vec(),
+                // Encrypted with a raw key before key derivation existed
+                None if passphrase.len() == 32 => passphrase.clone(),
+                None => return Err(DbError::Encryption(
+                    "Database has no key derivation parameters; pass the old key as a raw key".to_string()
+                ).into()),
+            },
+        };
+        let (new_key, new_params) = match new {
+            KeySource::Raw(key) => (key.clone(), None),
+            KeySource::Passphrase(passphrase) => {
+                let params = self.pending_kdf_params()?;
+                (params.derive(passphrase)?.to_vec(), Some(params))
+            },
+        };
+        if old_key == new_key {
+            return Err(DbError::Encryption("The new key is the same as the old key".to_string()).into());
+        }
+        let mut old_crypto = Crypto::new(&old_key)?;
+        if self.legacy_encryption {
+            old_crypto.allow_legacy();
+        }
+        let mut new_crypto = match &new_params {
+            Some(params) => Crypto::with_kdf_id(&new_key, params.id())?,
+            None => Crypto::new(&new_key)?,
+        };
+
+        // Everything is re-encrypted and checked in memory, then written in
+        // one batch, so a failure leaves the old files in place
+        let mut ops: Vec<BatchOp> = Vec::new();
+        for path in self.encrypted_paths()? {
+            let data = backend.read(&path)?;
+            let not_new = || DbError::Encryption(format!("{} doesn't decrypt with the new key after rotation", path));
+            // Already done by an interrupted rotation
+            if Envelope::parse(&data)?.is_some_and(|envelope| envelope.key_id == new_crypto.key_id()) {
+                new_crypto.decrypt_for(&data, path.as_bytes()).map_err(|_| not_new())?;
+                continue;
+            }
+            let plaintext = old_crypto.decrypt_for(&data, path.as_bytes())
+                .map_err(|e| DbError::Encryption(format!("{} can't be decrypted with the old key: {}", path, e)))?;
+            let encrypted = new_crypto.encrypt_for(&plaintext, path.as_bytes())?;
+            if new_crypto.decrypt_for(&encrypted, path.as_bytes()).map_err(|_| not_new())? != plaintext {
+                return Err(not_new().into());
+            }
+            ops.push((path, Some(encrypted)));
+        }
+        let rotated = ops.len();
+
+        match &new_params {
+            Some(params) => ops.push((KDF_PATH.to_string(), Some(params.to_bytes()?))),
+            None if backend.exists(KDF_PATH)? => ops.push((KDF_PATH.to_string(), None)),
+            None => {},
+        }
+        if backend.exists(PENDING_KDF_PATH)? {
+            ops.push((PENDING_KDF_PATH.to_string(), None));
+        }
+        backend.write_batch(&ops)?;
+        self.commit("Rotate encryption key")?;
+
+        // Older revisions are still encrypted with the old key
+        new_crypto.add_key(&old_key)?;
+        self.set_crypto(new_crypto);
+        Ok(rotated)
+    }
+
+    /// The parameters of the last committed state, which the old key was
+    /// derived with even if an interrupted rotation already replaced them in
+    /// the working tree.
+    fn committed_kdf_params(&self) -> Result<Option<KdfParams>> {
+        match &self.git {
+            Some(git) if git.head_id()?.is_some() => {
+                git.read_file_at("HEAD", KDF_PATH)?.map(|data| KdfParams::parse(&data)).transpose()
+            },
+            _ => KdfParams::load(self.storage.backend().as_ref(), KDF_PATH),
+        }
+    }
+
+    /// Parameters for the new passphrase: those of an interrupted rotation if
+    /// there is one, otherwise fresh ones, saved before any file is touched.
+    fn pending_kdf_params(&self) -> Result<KdfParams> {
+        let backend = self.storage.backend();
+        if let Some(params) = KdfParams::load(backend.as_ref(), PENDING_KDF_PATH)? {
+            return Ok(params);
+        }
+        // Interrupted after the new parameters replaced the old ones
+        if let Some(current) = KdfParams::load(backend.as_ref(), KDF_PATH)? {
+            if Some(&current) != self.committed_kdf_params()?.as_ref() {
+                return Ok(current);
+            }
+        }
+        let params = KdfParams::generate();
+        params.save(backend.as_ref(), PENDING_KDF_PATH)?;
+        Ok(params)
+    }
+
+    /// Every file encrypted with the encryption key: documents without a
+    /// data key, certificates and private keys, and wrapped data keys.
+    fn encrypted_paths(&self) -> Result<Vec<String>> {
+        let backend = self.storage.backend();
+        let mut paths = Vec::new();
+        for path in self.document_paths()? {
+            if !Envelope::parse(&backend.read(&path)?)?.is_some_and(|envelope| envelope.uses_data_key()) {
+                paths.push(path);
+            }
+        }
+        for file_name in backend.list(CERTS_DIR)? {
+            paths.push(format!("{}/{}", CERTS_DIR, file_name));
+        }
+        paths.extend(data_key_paths(backend.as_ref())?);
+        Ok(paths)
+    }
+
+    /// The path of every document in every collection.
+    pub(crate) fn document_paths(&self) -> Result<Vec<String>> {
+        let mut paths = Vec::new();
+        let mut add = |storage: &crate::Storage| -> Result<()> {
+            for id in storage.list()? {
+                paths.push(storage.relative_path(&id)?);
+            }
+            Ok(())
+        };
+        add(&self.storage)?;
+        for name in self.storage.list_collections()? {
+            add(&self.storage.collection(&name)?)?;
+        }
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_rotate_key() -> Result<()> {
+        let dir = tempdir()?;
+        let old = KeySource::Passphrase(b"old passphrase".to_vec());
+        let new = KeySource::Passphrase(b"new passphrase".to_vec());
+
+        let mut db = GithubDb::new(dir.path(), None)?;
+        db.set_key(&old)?;
+        let (cert, _key) = db.generate_certificate("alice")?;
+        db.create("a", json!({ "n": 1 }))?;
+        db.collection("users")?.create("b", json!({ "n": 2 }))?;
+
+        assert!(db.rotate_key(&KeySource::Passphrase(b"wrong".to_vec()), &new).is_err());
+        // The failed attempt leaves its parameters for the retry, nothing else
+        assert!(dir.path().join(PENDING_KDF_PATH).exists());
+        assert_eq!(db.rotate_key(&old, &new)?, 4);
+        assert!(!dir.path().join(PENDING_KDF_PATH).exists());
+        // The rotating handle can still read revisions under the old key
+        db.update("a", json!({ "n": 3 }))?;
+        assert_eq!(db.read_at("a", "HEAD~2")?.data, json!({ "n": 1 }));
+
+        let mut reopened = GithubDb::new(dir.path(), None)?;
+        reopened.set_key(&new)?;
+        assert!(reopened.verify_certificate(&cert)?);
+        assert_eq!(reopened.collection("users")?.read("b")?.data, json!({ "n": 2 }));
+
+        let mut stale = GithubDb::new(dir.path(), None)?;
+        assert!(stale.set_key(&old).is_err());
+
+        // Rotating to a raw key drops the derivation parameters
+        let raw = KeySource::Raw(vec![7u8; 32]);
+        assert_eq!(reopened.rotate_key(&new, &raw)?, 4);
+        assert!(!dir.path().join(KDF_PATH).exists());
+        assert_eq!(reopened.read("a")?.data, json!({ "n": 3 }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupted_rotation_resumes() -> Result<()> {
+        let dir = tempdir()?;
+        let old = KeySource::Raw(vec![1u8; 32]);
+        let new = KeySource::Raw(vec![2u8; 32]);
+        let mut db = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        db.create("a", json!({ "n": 1 }))?;
+        db.create("b", json!({ "n": 2 }))?;
+
+        // Simulate a crash after the first file was rewritten
+        let backend = db.storage.backend().clone();
+        let path = db.storage.relative_path("a")?;
+        let plaintext = Crypto::new(&[1u8; 32])?.decrypt_for(&backend.read(&path)?, path.as_bytes())?;
+        backend.write(&path, &Crypto::new(&[2u8; 32])?.encrypt_for(&plaintext, path.as_bytes())?)?;
+
+        assert_eq!(db.rotate_key(&old, &new)?, 1);
+        assert_eq!(db.read("a")?.data, json!({ "n": 1 }));
+        assert_eq!(db.read("b")?.data, json!({ "n": 2 }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_rotation_writes_nothing() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        db.create("a", json!({ "n": 1 }))?;
+        db.create("b", json!({ "n": 2 }))?;
+        let stored = std::fs::read(dir.path().join("a.json"))?;
+
+        // The second document can't be decrypted with the old key
+        let backend = db.storage.backend().clone();
+        backend.write("b.json", &Crypto::new(&[3u8; 32])?.encrypt_for(b"{}", b"b.json")?)?;
+        assert!(db.rotate_key(&KeySource::Raw(vec![1u8; 32]), &KeySource::Raw(vec![2u8; 32])).is_err());
+        assert_eq!(std::fs::read(dir.path().join("a.json"))?, stored);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotation_upgrades_legacy_files() -> Result<()> {
+        use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit};
+
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        let (cert, _key) = db.generate_certificate("alice")?;
+        db.create("a", json!({ "n": 1 }))?;
+
+        // Rewrite both files in the layout from before the envelope
+        let backend = db.storage.backend().clone();
+        let cipher = Aes256Gcm::new_from_slice(&[1u8; 32]).unwrap();
+        let mut legacy = Crypto::new(&[1u8; 32])?;
+        legacy.allow_legacy();
+        for path in ["a.json", "certs/alice.cert"] {
+            let plaintext = legacy.decrypt_for(&backend.read(path)?, path.as_bytes())?;
+            let mut data = vec![7u8; 12];
+            data.extend(cipher.encrypt(aes_gcm::Nonce::from_slice(&[7u8; 12]), plaintext.as_slice()).unwrap());
+            backend.write(path, &data)?;
+        }
+        db.commit("Legacy layout")?;
+
+        let db = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        assert!(db.read("a").unwrap_err().to_string().contains("legacy"));
+        assert!(db.verify_certificate(&cert).is_err());
+
+        let mut db = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        db.allow_legacy_encryption();
+        assert_eq!(db.read("a")?.data, json!({ "n": 1 }));
+        assert_eq!(db.rotate_key(&KeySource::Raw(vec![1u8; 32]), &KeySource::Raw(vec![2u8; 32]))?, 3);
+
+        let upgraded = GithubDb::new(dir.path(), Some(&[2u8; 32]))?;
+        assert_eq!(upgraded.read("a")?.data, json!({ "n": 1 }));
+        assert!(upgraded.verify_certificate(&cert)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_passphrase_unlocks_legacy_files() -> Result<()> {
+        use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit};
+
+        let dir = tempdir()?;
+        let passphrase = KeySource::Passphrase(b"passphrase".to_vec());
+        let mut db = GithubDb::new(dir.path(), None)?;
+        db.set_key(&passphrase)?;
+        let doc = db.create("a", json!({ "n": 1 }))?;
+
+        // Written without a header by a version from before the envelope
+        let backend = db.storage.backend().clone();
+        let key = KdfParams::load(backend.as_ref(), KDF_PATH)?.unwrap().derive(b"passphrase")?;
+        let cipher = Aes256Gcm::new_from_slice(&key[..]).unwrap();
+        let mut data = vec![7u8; 12];
+        data.extend(cipher.encrypt(aes_gcm::Nonce::from_slice(&[7u8; 12]), serde_json::to_vec(&doc)?.as_slice()).unwrap());
+        backend.write("a.json", &data)?;
+
+        let mut reopened = GithubDb::new(dir.path(), None)?;
+        let err = reopened.set_key(&passphrase).unwrap_err().to_string();
+        assert!(err.contains("--allow-legacy-encryption") && err.contains("rotate"), "{}", err);
+
+        let mut reopened = GithubDb::new(dir.path(), None)?;
+        reopened.allow_legacy_encryption();
+        reopened.set_key(&passphrase)?;
+        assert_eq!(reopened.read("a")?.data, json!({ "n": 1 }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotation_rewraps_data_keys() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), Some(&[1u8; 32]))?;
+        db.enable_data_keys()?;
+        db.create("a", json!({ "n": 1 }))?;
+        db.collection("users")?.create("b", json!({ "n": 2 }))?;
+        let stored = std::fs::read(dir.path().join("a.json"))?;
+
+        // Only the two wrapped keys are re-encrypted
+        assert_eq!(db.rotate_key(&KeySource::Raw(vec![1u8; 32]), &KeySource::Raw(vec![2u8; 32]))?, 2);
+        assert_eq!(std::fs::read(dir.path().join("a.json"))?, stored);
+
+        let mut reopened = GithubDb::new(dir.path(), Some(&[2u8; 32]))?;
+        assert_eq!(reopened.collection("users")?.read("b")?.data, json!({ "n": 2 }));
+        // The document blob didn't change, so neither did its history
+        assert_eq!(reopened.history("a")?.len(), 1);
+        reopened.update("a", json!({ "n": 3 }))?;
+        assert_eq!(reopened.read("a")?.data, json!({ "n": 3 }));
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+use crate::cert::CERTS_DIR;
+use crate::{CommitInfo, DbError, DocumentId, GitManager};
+use anyhow::Result;
+use base64::Engine;
+use git2::Sort;
+use openssl::{
+    asn1::Asn1Time,
+    cms::{CMSOptions, CmsContentInfo},
+    pkey::{PKey, Private},
+    stack::Stack,
+    x509::X509,
+};
+use serde::Serialize;
+use std::cmp::Ordering;
+
+const BEGIN: &str = "-----BEGIN SIGNED MESSAGE-----";
+const END: &str = "-----END SIGNED MESSAGE-----";
+
+/// Signs commits with a certificate and its private key. Signatures are
+/// detached CMS structures stored in the commit's `gpgsig` header, the same
+/// format `gpgsm` and `gitsign` use for X.509-signed commits.
+pub struct CommitSigner {
+    cert: X509,
+    key: PKey<Private>,
+}
+
+impl CommitSigner {
+    /// Takes the PEM certificate and private key written by
+    /// `generate-cert`.
+    pub fn new(cert_pem: &[u8], key_pem: &[u8]) -> Result<Self> {
+        let cert = X509::from_pem(cert_pem)
+            .map_err(|e| DbError::Certificate(format!("Invalid certificate: {}", e)))?;
+        let key = PKey::private_key_from_pem(key_pem)
+            .map_err(|e| DbError::Certificate(format!("Invalid private key: {}", e)))?;
+        if !cert.public_key()?.public_eq(&key) {
+            return Err(DbError::Certificate("Private key does not match the certificate".to_string()).into());
+        }
+        Ok(Self { cert, key })
+    }
+
+    pub(crate) fn sign(&self, data: &[u8]) -> Result<String> {
+        let cms = CmsContentInfo::sign(
+            Some(&self.cert),
+            Some(&self.key),
+            None,
+            Some(data),
+            CMSOptions::DETACHED | CMSOptions::BINARY,
+        )?;
+        let encoded = base64::engine::general_purpose::STANDARD.encode(cms.to_der()?);
+
+        let mut armored = format!("{}\n", BEGIN);
+        for line in encoded.as_bytes().chunks(64) {
+            armored.push_str(std::str::from_utf8(line)?);
+            armored.push('\n');
+        }
+        armored.push_str(END);
+        Ok(armored)
+    }
+}
+
+/// Returns the index of the certificate among `candidates` whose key made
+/// `signature` over `data`. Certificates embedded in the signature itself are
+/// ignored, so only the given certificates can vouch for a commit.
+pub(crate) fn find_signer(signature: &str, data: &[u8], candidates: &[X509]) -> Result<Option<usize>> {
+    let encoded: String = signature.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && *line != BEGIN && *line != END)
+        .collect();
+    let der = base64::engine::general_purpose::STANDARD.decode(encoded)
+        .map_err(|e| DbError::Certificate(format!("Malformed commit signature: {}", e)))?;
+
+    for (i, cert) in candidates.iter().enumerate() {
+        let mut cms = CmsContentInfo::from_der(&der)
+            .map_err(|e| DbError::Certificate(format!("Malformed commit signature: {}", e)))?;
+        let mut certs = Stack::new()?;
+        certs.push(cert.clone())?;
+        let flags = CMSOptions::BINARY | CMSOptions::NOINTERN | CMSOptions::NO_SIGNER_CERT_VERIFY;
+        if cms.verify(Some(&certs), None, Some(data), None, flags).is_ok() {
+            return Ok(Some(i));
+        }
+    }
+    Ok(None)
+}
+
+/// Why `cert` couldn't have signed a commit made at `timestamp` (seconds
+/// since the epoch), or `None` if it was valid then.
+pub(crate) fn validity_problem(cert: &X509, timestamp: i64) -> Result<Option<&'static str>> {
+    let time = Asn1Time::from_unix(timestamp)?;
+    if cert.not_before().compare(&time)? == Ordering::Greater {
+        Ok(Some("certificate not yet valid when the commit was made"))
+    } else if cert.not_after().compare(&time)? == Ordering::Less {
+        Ok(Some("certificate expired when the commit was made"))
+    } else {
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum SignatureStatus {
+    /// Signed by `signer`, whose certificate was stored in the database and
+    /// within its validity period when the commit was made.
+    Valid { signer: String },
+    Unsigned,
+    Invalid { reason: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CommitVerification {
+    pub commit: CommitInfo,
+    #[serde(flatten)]
+    pub status: SignatureStatus,
+}
+
+impl CommitVerification {
+    /// Every commit must carry a valid signature, including those that only
+    /// manage certificates or keys.
+    pub fn is_ok(&self) -> bool {
+        matches!(self.status, SignatureStatus::Valid { .. })
+    }
+}
+
+/// What verifying a commit's signature needs to know about it.
+pub(crate) struct SignedCommit {
+    pub info: CommitInfo,
+    /// The first parent, whose trusted certificates the commit is checked
+    /// against.
+    pub parent: Option<String>,
+    /// The armored signature and the commit contents it covers.
+    pub signature: Option<(String, Vec<u8>)>,
+    /// Certificates the commit added, replaced (username, file contents) or
+    /// removed (username, `None`) relative to its first parent.
+    pub cert_changes: Vec<(String, Option<Vec<u8>>)>,
+}
+
+impl GitManager {
+    /// Every commit reachable from HEAD, parents before their children.
+    pub(crate) fn signed_history(&self) -> Result<Vec<SignedCommit>> {
+        let head = match self.head() {
+            Ok(head) => head.peel_to_commit()?,
+            Err(_) => return Ok(Vec::new()),
+        };
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        revwalk.push(head.id())?;
+
+        let mut history = Vec::new();
+        for oid in revwalk {
+            let oid = oid?;
+            let commit = self.repo.find_commit(oid)?;
+            let tree = commit.tree()?;
+
+            let signature = match self.repo.extract_signature(&oid, Some("gpgsig")) {
+                Ok((signature, data)) => Some((String::from_utf8_lossy(&signature).into_owned(), data.to_vec())),
+                Err(e) if e.code() == git2::ErrorCode::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+
+            let parent_tree = match commit.parents().next() {
+                Some(parent) => Some(parent.tree()?),
+                None => None,
+            };
+            let mut diff_options = git2::DiffOptions::new();
+            diff_options.pathspec(CERTS_DIR);
+            let diff = self.repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), Some(&mut diff_options))?;
+            let mut cert_changes = Vec::new();
+            for delta in diff.deltas() {
+                let username = delta.new_file().path().or(delta.old_file().path())
+                    .and_then(|path| path.file_name())
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.strip_suffix(".cert"))
+                    .and_then(|name| DocumentId::from_escaped(name).ok());
+                if let Some(username) = username {
+                    let contents = match delta.status() {
+                        git2::Delta::Deleted => None,
+                        _ => Some(self.read_blob(delta.new_file().id())?),
+                    };
+                    cert_changes.push((username.to_string(), contents));
+                }
+            }
+
+            history.push(SignedCommit {
+                info: CommitInfo::from_commit(&commit),
+                parent: commit.parent_ids().next().map(|id| id.to_string()),
+                signature,
+                cert_changes,
+            });
+        }
+        Ok(history)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use crate::cert::CertManager;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_sign_and_find_signer() -> Result<()> {
+        let certs = CertManager::new(Arc::new(MemoryBackend::new()), None)?;
+        let (alice_cert, alice_key) = certs.generate_cert("alice")?;
+        let (bob_cert, bob_key) = certs.generate_cert("bob")?;
+
+        assert!(CommitSigner::new(&alice_cert, &bob_key).is_err());
+        let signer = CommitSigner::new(&alice_cert, &alice_key)?;
+        let signature = signer.sign(b"tree 1234\n\nmessage")?;
+        assert!(signature.starts_with(BEGIN) && signature.ends_with(END));
+
+        let candidates = vec![X509::from_pem(&bob_cert)?, X509::from_pem(&alice_cert)?];
+        assert_eq!(find_signer(&signature, b"tree 1234\n\nmessage", &candidates)?, Some(1));
+        assert_eq!(find_signer(&signature, b"tree 1234\n\ntampered", &candidates)?, None);
+        assert_eq!(find_signer(&signature, b"tree 1234\n\nmessage", &candidates[..1])?, None);
+
+        // A signature by a key that isn't stored doesn't verify even though
+        // it embeds its own certificate
+        let bob = CommitSigner::new(&bob_cert, &bob_key)?;
+        let signature = bob.sign(b"data")?;
+        assert_eq!(find_signer(&signature, b"data", &candidates[1..])?, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use crate::{DbError, GitManager};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// A named, frozen state of the database, stored as an annotated tag.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    /// The commit the snapshot points at.
+    pub commit: String,
+    pub timestamp: i64,
+    pub message: String,
+    pub tagger: String,
+}
+
+impl GitManager {
+    /// Tags the current commit as `name`. Snapshot names can be passed
+    /// anywhere a revision is accepted, e.g. to [`GitManager::read_file_at`].
+    pub fn create_snapshot(&self, name: &str, message: Option<&str>) -> Result<Snapshot> {
+        if !git2::Reference::is_valid_name(&format!("refs/tags/{}", name)) {
+            return Err(DbError::Git(format!("Invalid snapshot name {}", name)).into());
+        }
+        if self.repo.find_reference(&format!("refs/tags/{}", name)).is_ok() {
+            return Err(DbError::Git(format!("Snapshot {} already exists", name)).into());
+        }
+
+        let head = self.resolve("HEAD")?;
+        let message = message.map(str::to_string).unwrap_or_else(|| format!("Snapshot {}", name));
+        let tag_id = self.repo.tag(name, head.as_object(), &self.author()?, &message, false)?;
+        self.snapshot(name, tag_id)
+    }
+
+    fn snapshot(&self, name: &str, tag_id: git2::Oid) -> Result<Snapshot> {
+        let tag = self.repo.find_tag(tag_id)?;
+        let tagger = tag.tagger();
+        Ok(Snapshot {
+            name: name.to_string(),
+            commit: tag.target_id().to_string(),
+            timestamp: tagger.as_ref().map(|t| t.when().seconds()).unwrap_or_default(),
+            message: tag.message().unwrap_or_default().trim_end().to_string(),
+            tagger: tagger.as_ref().and_then(|t| t.name()).unwrap_or_default().to_string(),
+        })
+    }
+
+    /// Every snapshot, oldest first. Lightweight tags aren't snapshots and
+    /// are skipped.
+    pub fn list_snapshots(&self) -> Result<Vec<Snapshot>> {
+        let mut snapshots = Vec::new();
+        for name in self.repo.tag_names(None)?.iter().flatten() {
+            let target = self.repo.refname_to_id(&format!("refs/tags/{}", name))?;
+            if self.repo.find_tag(target).is_ok() {
+                snapshots.push(self.snapshot(name, target)?);
+            }
+        }
+        snapshots.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.name.cmp(&b.name)));
+        Ok(snapshots)
+    }
+
+    pub fn delete_snapshot(&self, name: &str) -> Result<()> {
+        if self.repo.find_reference(&format!("refs/tags/{}", name)).is_err() {
+            return Err(DbError::Git(format!("Snapshot {} does not exist", name)).into());
+        }
+        self.repo.tag_delete(name)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_snapshots() -> Result<()> {
+        let dir = tempdir()?;
+        let git = GitManager::new(dir.path())?;
+        assert!(git.create_snapshot("empty", None).is_err());
+
+        fs::write(dir.path().join("a.json"), "1")?;
+        git.commit("first")?;
+        let snapshot = git.create_snapshot("release-1.0", Some("Configuration for 1.0"))?;
+        assert_eq!(snapshot.message, "Configuration for 1.0");
+        assert!(git.create_snapshot("release-1.0", None).is_err());
+        assert!(git.create_snapshot("bad..name", None).is_err());
+
+        fs::write(dir.path().join("a.json"), "2")?;
+        git.commit("second")?;
+        assert_eq!(git.read_file_at("release-1.0", "a.json")?.as_deref(), Some(&b"1"[..]));
+
+        let names: Vec<_> = git.list_snapshots()?.into_iter().map(|s| s.name).collect();
+        assert_eq!(names, vec!["release-1.0"]);
+        git.delete_snapshot("release-1.0")?;
+        assert!(git.list_snapshots()?.is_empty());
+        assert!(git.delete_snapshot("release-1.0").is_err());
+
+        Ok(())
+    }
+}
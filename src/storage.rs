@@ -1,63 +1,132 @@
 use anyhow::Result;
-use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
+use crate::backend::{BatchOp, FsBackend, StorageBackend};
+use crate::DocumentId;
+use std::path::Path;
+use std::sync::Arc;
 
+pub(crate) const COLLECTIONS_DIR: &str = "collections";
+
+/// Maps document IDs to files in a [`StorageBackend`].
+#[derive(Clone)]
 pub struct Storage {
-    base_path: PathBuf,
+    backend: Arc<dyn StorageBackend>,
+    prefix: String,
 }
 
 impl Storage {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let base_path = path.as_ref().to_path_buf();
-        fs::create_dir_all(&base_path)?;
-        
-        Ok(Self { base_path })
+        Ok(Self::with_backend(Arc::new(FsBackend::new(path)?)))
+    }
+
+    pub fn with_backend(backend: Arc<dyn StorageBackend>) -> Self {
+        Self { backend, prefix: String::new() }
+    }
+
+    pub fn backend(&self) -> &Arc<dyn StorageBackend> {
+        &self.backend
     }
 
-    fn get_file_path(&self, id: &str) -> PathBuf {
-        self.base_path.join(format!("{}.json", id))
+    /// Returns storage for the named collection, kept under
+    /// `collections/<name>/` relative to this storage's base path.
+    pub fn collection(&self, name: &str) -> Result<Self> {
+        let name = DocumentId::new(name)?;
+        Ok(Self {
+            backend: self.backend.clone(),
+            prefix: format!("{}/{}", COLLECTIONS_DIR, name.escaped()),
+        })
+    }
+
+    pub fn list_collections(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self.backend.list_dirs(COLLECTIONS_DIR)?
+            .iter()
+            .filter_map(|name| DocumentId::from_escaped(name).ok())
+            .map(|name| name.to_string())
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn drop_collection(&self, name: &str) -> Result<()> {
+        self.backend.delete_dir(&self.collection(name)?.prefix)
+    }
+
+    /// The document's path relative to the repository root, as used by git.
+    pub fn relative_path(&self, id: &str) -> Result<String> {
+        let file_name = format!("{}.json", DocumentId::new(id)?.escaped());
+        if self.prefix.is_empty() {
+            Ok(file_name)
+        } else {
+            Ok(format!("{}/{}", self.prefix, file_name))
+        }
     }
 
     pub fn write(&self, id: &str, data: &[u8]) -> Result<()> {
-        let path = self.get_file_path(id);
-        let mut file = File::create(path)?;
-        file.write_all(data)?;
-        Ok(())
+        self.backend.write(&self.relative_path(id)?, data)
+    }
+
+    /// Writes (`Some`) or deletes (`None`) several documents in one call.
+    pub fn write_batch(&self, ops: &[(&str, Option<Vec<u8>>)]) -> Result<()> {
+        let ops = ops.iter()
+            .map(|(id, data)| Ok((self.relative_path(id)?, data.clone())))
+            .collect::<Result<Vec<BatchOp>>>()?;
+        self.backend.write_batch(&ops)
     }
 
     pub fn read(&self, id: &str) -> Result<Vec<u8>> {
-        let path = self.get_file_path(id);
-        let mut file = File::open(path)?;
-        let mut data = Vec::new();
-        file.read_to_end(&mut data)?;
-        Ok(data)
+        self.backend.read(&self.relative_path(id)?)
+    }
+
+    pub fn exists(&self, id: &str) -> Result<bool> {
+        self.backend.exists(&self.relative_path(id)?)
     }
 
     pub fn delete(&self, id: &str) -> Result<()> {
-        let path = self.get_file_path(id);
-        fs::remove_file(path)?;
-        Ok(())
+        self.backend.delete(&self.relative_path(id)?)
+    }
+
+    /// The directory holding this storage's documents, relative to the
+    /// repository root.
+    pub fn dir(&self) -> &str {
+        &self.prefix
     }
 
     pub fn list(&self) -> Result<Vec<String>> {
-        let mut files = Vec::new();
-        for entry in fs::read_dir(&self.base_path)? {
-            let entry = entry?;
-            if let Some(file_name) = entry.file_name().to_str() {
-                if file_name.ends_with(".json") {
-                    files.push(file_name[..file_name.len() - 5].to_string());
-                }
-            }
+        Ok(Self::document_ids(self.backend.list(&self.prefix)?))
+    }
+
+    /// Splits a repository path into the collection (`None` for the root)
+    /// and document ID it stores, or `None` if it isn't a document.
+    pub fn parse_path(path: &str) -> Option<(Option<String>, String)> {
+        let decode = |name: &str| DocumentId::from_escaped(name).ok().map(|id| id.to_string());
+        let (dir, file_name) = match path.rsplit_once('/') {
+            Some((dir, file_name)) => (Some(dir), file_name),
+            None => (None, path),
+        };
+        let id = decode(file_name.strip_suffix(".json")?)?;
+        match dir {
+            None => Some((None, id)),
+            Some(dir) => {
+                let name = dir.strip_prefix(COLLECTIONS_DIR)?.strip_prefix('/')?;
+                Some((Some(decode(name)?), id))
+            },
         }
-        Ok(files)
+    }
+
+    /// Maps the file names in a document directory back to document IDs,
+    /// skipping anything that isn't a document.
+    pub fn document_ids(file_names: Vec<String>) -> Vec<String> {
+        file_names.iter()
+            .filter_map(|name| name.strip_suffix(".json"))
+            .filter_map(|name| DocumentId::from_escaped(name).ok())
+            .map(|id| id.to_string())
+            .collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
+    use crate::{DbError, MemoryBackend};
     use tempfile::tempdir;
 
     #[test]
@@ -80,4 +149,50 @@ mod tests {
         storage.delete(id).unwrap();
         assert!(storage.read(id).is_err());
     }
+
+    #[test]
+    fn test_collection_paths() {
+        let storage = Storage::with_backend(Arc::new(MemoryBackend::new()));
+        let users = storage.collection("users").unwrap();
+
+        assert_eq!(storage.relative_path("a").unwrap(), "a.json");
+        assert_eq!(users.relative_path("a").unwrap(), "collections/users/a.json");
+
+        users.write("a", b"{}").unwrap();
+        assert!(storage.backend().exists("collections/users/a.json").unwrap());
+        assert!(storage.list().unwrap().is_empty());
+        assert_eq!(storage.list_collections().unwrap(), vec!["users"]);
+    }
+
+    #[test]
+    fn test_parse_path() {
+        let storage = Storage::with_backend(Arc::new(MemoryBackend::new()));
+        let users = storage.collection("team/a").unwrap();
+        let path = users.relative_path("x y").unwrap();
+        assert_eq!(Storage::parse_path(&path), Some((Some("team/a".to_string()), "x y".to_string())));
+        assert_eq!(Storage::parse_path("a.json"), Some((None, "a".to_string())));
+        assert_eq!(Storage::parse_path("certs/alice.cert"), None);
+        assert_eq!(Storage::parse_path("other/a.json"), None);
+    }
+
+    #[test]
+    fn test_ids_cannot_escape_base_path() {
+        let dir = tempdir().unwrap();
+        let base = dir.path().join("db");
+        let storage = Storage::new(&base).unwrap();
+
+        storage.write("../outside", b"x").unwrap();
+        storage.write("a/b", b"y").unwrap();
+        assert!(!dir.path().join("outside.json").exists());
+        assert!(base.join("%2E.%2Foutside.json").is_file());
+        assert!(base.join("a%2Fb.json").is_file());
+
+        let mut ids = storage.list().unwrap();
+        ids.sort();
+        assert_eq!(ids, vec!["../outside", "a/b"]);
+        assert_eq!(storage.read("a/b").unwrap(), b"y");
+
+        let err = storage.write("", b"z").unwrap_err();
+        assert!(matches!(err.downcast_ref::<DbError>(), Some(DbError::InvalidId(_))));
+    }
 }
@@ -0,0 +1,141 @@
+use crate::{BatchOp, Collection, DbError, Document};
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+/// A set of document writes that is applied to the working tree and committed
+/// as a single git commit. Reads through the transaction see its own staged
+/// writes.
+pub struct Transaction<'a> {
+    collection: &'a Collection<'a>,
+    staged: BTreeMap<String, Option<Document>>,
+    operations: Vec<String>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(collection: &'a Collection<'a>) -> Self {
+        Self {
+            collection,
+            staged: BTreeMap::new(),
+            operations: Vec::new(),
+        }
+    }
+
+    fn current(&self, id: &str) -> Result<Option<Document>> {
+        match self.staged.get(id) {
+            Some(doc) => Ok(doc.clone()),
+            None => self.collection.load(id, None),
+        }
+    }
+
+    pub fn read(&self, id: &str) -> Result<Document> {
+        self.current(id)?
+            .ok_or_else(|| DbError::Storage(format!("Document {} not found", self.collection.qualified(id))).into())
+    }
+
+    pub fn create(&mut self, id: &str, data: serde_json::Value) -> Result<Document> {
+        if self.current(id)?.is_some() {
+            return Err(DbError::Storage(format!("Document {} already exists", self.collection.qualified(id))).into());
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        let doc = Document {
+            id: id.to_string(),
+            data,
+            created_at: now,
+            updated_at: now,
+            version: 1,
+        };
+
+        self.staged.insert(id.to_string(), Some(doc.clone()));
+        self.operations.push(format!("create {}", self.collection.qualified(id)));
+        Ok(doc)
+    }
+
+    pub fn update(&mut self, id: &str, data: serde_json::Value) -> Result<Document> {
+        self.update_checked(id, None, data)
+    }
+
+    /// Like [`Transaction::update`], but fails with [`DbError::Conflict`] if
+    /// the document is no longer at `expected_version`.
+    pub fn update_if(&mut self, id: &str, expected_version: u64, data: serde_json::Value) -> Result<Document> {
+        self.update_checked(id, Some(expected_version), data)
+    }
+
+    fn update_checked(&mut self, id: &str, expected_version: Option<u64>, data: serde_json::Value) -> Result<Document> {
+        let mut doc = self.read(id)?;
+        doc.check_version(expected_version)?;
+        doc.data = data;
+        doc.version += 1;
+        doc.updated_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+
+        self.staged.insert(id.to_string(), Some(doc.clone()));
+        self.operations.push(format!("update {}", self.collection.qualified(id)));
+        Ok(doc)
+    }
+
+    pub fn delete(&mut self, id: &str) -> Result<()> {
+        self.read(id)?;
+        self.staged.insert(id.to_string(), None);
+        self.operations.push(format!("delete {}", self.collection.qualified(id)));
+        Ok(())
+    }
+
+    fn message(&self) -> String {
+        let mut message = format!("Transaction ({} operations)\n", self.operations.len());
+        for operation in &self.operations {
+            message.push_str(&format!("\n- {}", operation));
+        }
+        message
+    }
+
+    /// Writes every staged document and commits once. If any write or the
+    /// commit fails, the files touched so far are put back as they were.
+    pub(crate) fn commit(self) -> Result<()> {
+        if self.staged.is_empty() {
+            return Ok(());
+        }
+
+        // Encode everything up front so an encryption failure never leaves
+        // a partially written transaction behind. New data keys are written
+        // along with their documents.
+        let storage = &self.collection.storage;
+        let mut writes: Vec<BatchOp> = Vec::with_capacity(self.staged.len());
+        for (id, doc) in &self.staged {
+            match doc {
+                Some(doc) => writes.extend(self.collection.encode(doc)?),
+                None => writes.push((storage.relative_path(id)?, None)),
+            }
+        }
+
+        let backend = storage.backend();
+        let mut originals = Vec::with_capacity(writes.len());
+        let mut ops = Vec::with_capacity(writes.len());
+        for (path, data) in writes {
+            let original = if backend.exists(&path)? { Some(backend.read(&path)?) } else { None };
+            // A document created and deleted within the transaction never hits storage
+            if data.is_some() || original.is_some() {
+                ops.push((path.clone(), data));
+            }
+            originals.push((path, original));
+        }
+
+        let result = backend.write_batch(&ops)
+            .and_then(|_| self.collection.db.commit(&self.message()));
+
+        if let Err(e) = result {
+            for (path, original) in originals.into_iter().rev() {
+                let _ = match original {
+                    Some(data) => backend.write(&path, &data),
+                    None => backend.delete(&path),
+                };
+            }
+            return Err(e);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{ChangeEvent, EventKind, Filter, GithubDb};
+use anyhow::Result;
+use git2::Oid;
+use std::thread;
+use std::time::Duration;
+
+/// Which changes a [`Watcher`] reports.
+#[derive(Debug, Clone, Default)]
+pub struct WatchOptions {
+    /// Only report documents matching this filter: the new document for
+    /// creates and updates, the old one for deletes. Documents the filter
+    /// can't be evaluated on, e.g. because a field is missing, don't match.
+    pub filter: Option<Filter>,
+    /// Only report changes in this collection; `None` reports all of them.
+    pub collection: Option<String>,
+    /// Attach the document before and after each change.
+    pub include_values: bool,
+}
+
+/// Follows the current branch and reports the document changes of every
+/// commit that lands on it, whether written locally, by another process or
+/// brought in by a pull.
+pub struct Watcher {
+    cursor: Option<Oid>,
+    options: WatchOptions,
+}
+
+impl Watcher {
+    pub(crate) fn new(db: &GithubDb, since: Option<&str>, options: WatchOptions) -> Result<Self> {
+        let git = db.git()?;
+        let cursor = match since {
+            Some(rev) => Some(git.resolve(rev)?.id()),
+            None => git.head_id()?,
+        };
+        Ok(Self { cursor, options })
+    }
+
+    /// The last commit whose changes were reported. Pass it as `since` to
+    /// resume watching later without missing or repeating changes.
+    pub fn cursor(&self) -> Option<String> {
+        self.cursor.map(|oid| oid.to_string())
+    }
+
+    /// Returns the matching changes committed since the previous poll.
+    pub fn poll(&mut self, db: &GithubDb) -> Result<Vec<ChangeEvent>> {
+        let head = match db.git()?.head_id()? {
+            Some(head) if Some(head) != self.cursor => head,
+            _ => return Ok(Vec::new()),
+        };
+
+        // The filter needs the documents even if the caller doesn't
+        let load = self.options.include_values || self.options.filter.is_some();
+        let mut events = db.changes_between(self.cursor, head, load)?;
+        events.retain(|event| self.matches(event));
+        if !self.options.include_values {
+            for event in &mut events {
+                event.before = None;
+                event.after = None;
+            }
+        }
+
+        self.cursor = Some(head);
+        Ok(events)
+    }
+
+    /// Polls every `interval` and hands each change to `handler` until it
+    /// returns `false`.
+    pub fn run<F>(&mut self, db: &GithubDb, interval: Duration, mut handler: F) -> Result<()>
+    where
+        F: FnMut(ChangeEvent) -> Result<bool>,
+    {
+        loop {
+            for event in self.poll(db)? {
+                if !handler(event)? {
+                    return Ok(());
+                }
+            }
+            thread::sleep(interval);
+        }
+    }
+
+    fn matches(&self, event: &ChangeEvent) -> bool {
+        if self.options.collection.is_some() && event.collection != self.options.collection {
+            return false;
+        }
+        let filter = match &self.options.filter {
+            Some(filter) => filter,
+            None => return true,
+        };
+        let document = match event.kind {
+            EventKind::Deleted => &event.before,
+            _ => &event.after,
+        };
+        document.as_ref()
+            .is_some_and(|doc| filter.matches(doc).unwrap_or(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompactOptions, FilterCondition, FilterOp};
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_watch_reports_new_matching_changes() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db = GithubDb::new(dir.path(), None)?;
+        db.create("old", json!({ "status": "open" }))?;
+
+        let options = WatchOptions {
+            filter: Some(Filter::Condition(FilterCondition {
+                field: "status".to_string(),
+                op: FilterOp::Eq,
+                value: json!("open"),
+            })),
+            ..Default::default()
+        };
+        let mut watcher = db.watch(None, options)?;
+        assert!(watcher.poll(&db)?.is_empty());
+
+        // Written through a second handle, as another process would
+        let mut writer = GithubDb::new(dir.path(), None)?;
+        writer.create("a", json!({ "status": "open" }))?;
+        writer.create("b", json!({ "status": "closed" }))?;
+        writer.create("c", json!({ "title": "no status" }))?;
+        writer.delete("old")?;
+
+        let events = watcher.poll(&db)?;
+        let summary: Vec<_> = events.iter().map(|e| (e.kind, e.id.as_str())).collect();
+        assert_eq!(summary, vec![(EventKind::Created, "a"), (EventKind::Deleted, "old")]);
+        assert!(events[0].after.is_none());
+        assert!(watcher.poll(&db)?.is_empty());
+
+        // Rewritten history only reports what actually changed
+        writer.update("a", json!({ "status": "open", "n": 1 }))?;
+        let cursor = watcher.cursor();
+        writer.compact(&CompactOptions { keep_revisions: Some(1), ..Default::default() })?;
+        let mut resumed = db.watch(cursor.as_deref(), WatchOptions::default())?;
+        let events = resumed.poll(&db)?;
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].kind, events[0].id.as_str()), (EventKind::Updated, "a"));
+
+        Ok(())
+    }
+}